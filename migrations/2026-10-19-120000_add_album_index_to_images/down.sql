@@ -0,0 +1 @@
+DROP INDEX images_album_id_index_idx;
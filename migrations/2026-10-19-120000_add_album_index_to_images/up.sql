@@ -0,0 +1 @@
+CREATE INDEX images_album_id_index_idx ON images (album_id, index);
@@ -0,0 +1,6 @@
+ALTER TABLE images
+    DROP CONSTRAINT blob_fk;
+
+-- the wider file column is kept, as content addressed names do not fit
+-- into the old one
+DROP TABLE blobs;
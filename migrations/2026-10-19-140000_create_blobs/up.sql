@@ -0,0 +1,17 @@
+CREATE TABLE blobs (
+    file VARCHAR(80) PRIMARY KEY,
+    ref_count INTEGER NOT NULL DEFAULT 0
+);
+
+ALTER TABLE images
+    ALTER COLUMN file TYPE VARCHAR(80);
+
+INSERT INTO blobs (file, ref_count)
+    SELECT file, COUNT(*) FROM images
+        WHERE file IS NOT NULL
+        GROUP BY file;
+
+ALTER TABLE images
+    ADD CONSTRAINT blob_fk
+        FOREIGN KEY (file)
+        REFERENCES blobs(file);
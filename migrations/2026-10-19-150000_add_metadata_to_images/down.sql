@@ -0,0 +1,5 @@
+ALTER TABLE images
+    DROP COLUMN width,
+    DROP COLUMN height,
+    DROP COLUMN content_type,
+    DROP COLUMN size;
@@ -0,0 +1,5 @@
+ALTER TABLE images
+    ADD COLUMN width INTEGER,
+    ADD COLUMN height INTEGER,
+    ADD COLUMN content_type VARCHAR(255),
+    ADD COLUMN size BIGINT;
@@ -0,0 +1,5 @@
+ALTER TABLE images
+    DROP COLUMN source;
+
+ALTER TABLE albums
+    DROP COLUMN archive;
@@ -0,0 +1,5 @@
+ALTER TABLE albums
+    ADD COLUMN archive BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE images
+    ADD COLUMN source VARCHAR(255);
@@ -0,0 +1,5 @@
+DROP INDEX images_link_checked_at_idx;
+
+ALTER TABLE images
+    DROP COLUMN link_status,
+    DROP COLUMN link_checked_at;
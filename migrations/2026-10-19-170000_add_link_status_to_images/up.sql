@@ -0,0 +1,5 @@
+ALTER TABLE images
+    ADD COLUMN link_status INTEGER,
+    ADD COLUMN link_checked_at TIMESTAMP;
+
+CREATE INDEX images_link_checked_at_idx ON images (link_checked_at) WHERE file IS NULL;
@@ -0,0 +1,9 @@
+UPDATE images
+    SET url = source,
+        source = NULL
+    WHERE file IS NULL
+        AND source ~* '^https?://([a-z0-9-]+\.)*imgur\.com/.*\.gifv$'
+        AND url = regexp_replace(source, '\.gifv$', '.mp4', 'i');
+
+ALTER TABLE images
+    DROP COLUMN kind;
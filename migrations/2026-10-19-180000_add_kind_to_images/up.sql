@@ -0,0 +1,17 @@
+ALTER TABLE images
+    ADD COLUMN kind VARCHAR(16) NOT NULL DEFAULT 'image';
+
+-- Imgur serves .gifv links as html pages embedding an mp4 video. The
+-- original link is kept as the source of the image, which also allows
+-- reverting this migration.
+UPDATE images
+    SET source = url,
+        url = regexp_replace(url, '\.gifv$', '.mp4', 'i')
+    WHERE file IS NULL
+        AND source IS NULL
+        AND url ~* '^https?://([a-z0-9-]+\.)*imgur\.com/.*\.gifv$';
+
+UPDATE images
+    SET kind = 'video'
+    WHERE content_type LIKE 'video/%'
+        OR url ~* '\.(mp4|webm)$';
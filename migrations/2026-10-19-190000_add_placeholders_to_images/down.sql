@@ -0,0 +1,3 @@
+ALTER TABLE images
+    DROP COLUMN blurhash,
+    DROP COLUMN color;
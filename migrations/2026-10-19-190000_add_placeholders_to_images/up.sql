@@ -0,0 +1,3 @@
+ALTER TABLE images
+    ADD COLUMN blurhash VARCHAR(64),
+    ADD COLUMN color VARCHAR(7);
@@ -0,0 +1,17 @@
+CREATE TABLE import_jobs (
+    id SERIAL PRIMARY KEY,
+    album_id INTEGER NOT NULL REFERENCES albums (id) ON DELETE CASCADE,
+    url VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'queued',
+    total INTEGER,
+    fetched INTEGER NOT NULL DEFAULT 0,
+    inserted INTEGER NOT NULL DEFAULT 0,
+    skipped INTEGER NOT NULL DEFAULT 0,
+    errors TEXT[] NOT NULL DEFAULT '{}',
+    warnings TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX import_jobs_status ON import_jobs (status);
+CREATE INDEX import_jobs_album_id ON import_jobs (album_id);
@@ -0,0 +1,9 @@
+ALTER TABLE import_jobs
+    DROP COLUMN removed;
+
+ALTER TABLE images
+    DROP COLUMN upstream,
+    DROP COLUMN removed_upstream;
+
+ALTER TABLE albums
+    DROP COLUMN source;
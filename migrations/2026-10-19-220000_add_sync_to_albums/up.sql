@@ -0,0 +1,20 @@
+ALTER TABLE albums
+    ADD COLUMN source VARCHAR;
+
+ALTER TABLE images
+    ADD COLUMN upstream VARCHAR,
+    ADD COLUMN removed_upstream BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE import_jobs
+    ADD COLUMN removed INTEGER NOT NULL DEFAULT 0;
+
+-- albums imported so far only have their images from the source album
+UPDATE albums SET source = (
+    SELECT url FROM import_jobs
+        WHERE import_jobs.album_id = albums.id
+        ORDER BY import_jobs.id DESC
+        LIMIT 1
+);
+
+-- their images are linked to the source album on the first sync, as only
+-- the images which are still in it are known to be imported
@@ -0,0 +1,3 @@
+-- Longer links may have been stored since, which do not fit into
+-- VARCHAR(255) anymore, so the columns are kept unbounded.
+SELECT 1;
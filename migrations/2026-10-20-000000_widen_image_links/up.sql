@@ -0,0 +1,4 @@
+-- links of mirrored and imported images are often longer than 255 characters
+ALTER TABLE images
+    ALTER COLUMN url TYPE VARCHAR,
+    ALTER COLUMN source TYPE VARCHAR;
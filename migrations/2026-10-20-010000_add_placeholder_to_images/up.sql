@@ -0,0 +1,3 @@
+-- the blurhash rendered as a data url, so it is not decoded on every page view
+ALTER TABLE images
+    ADD COLUMN placeholder TEXT;
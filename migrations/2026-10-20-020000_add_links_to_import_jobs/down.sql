@@ -0,0 +1,8 @@
+DELETE FROM import_jobs
+    WHERE links IS NOT NULL;
+
+ALTER TABLE import_jobs
+    DROP COLUMN insert_at,
+    DROP COLUMN captions,
+    DROP COLUMN links,
+    ALTER COLUMN url SET NOT NULL;
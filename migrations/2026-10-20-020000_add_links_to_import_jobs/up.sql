@@ -0,0 +1,6 @@
+-- jobs adding a list of links given by the user have no source album
+ALTER TABLE import_jobs
+    ALTER COLUMN url DROP NOT NULL,
+    ADD COLUMN links TEXT[],
+    ADD COLUMN captions TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN insert_at INTEGER NOT NULL DEFAULT 0;
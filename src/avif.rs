@@ -0,0 +1,380 @@
+//! Encodes images as AVIF, which is an AV1 key frame in a HEIF container.
+//!
+//! The frames are encoded with rav1e. The container only holds what decoders
+//! need for a still image: the color item, optionally an alpha item, their
+//! properties and the encoded data.
+
+use anyhow::{ensure, Context as _, Result};
+use image::RgbaImage;
+use rav1e::prelude::*;
+use std::convert::TryFrom;
+
+/// rav1e speed preset from 0 (slowest) to 10 (fastest). Transforms are
+/// encoded while the request waits, which makes speed more important than
+/// the last bit of compression.
+const SPEED: usize = 8;
+
+/// Smallest width and height of a frame rav1e encodes
+const MIN_FRAME_SIZE: u32 = 16;
+
+/// Identifies the alpha item as transparency of the color item
+const ALPHA_URN: &[u8] = b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0";
+
+/// An encoded AV1 frame with its `av1C` configuration
+struct Frame {
+    config: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Encode `image` as AVIF with a `quality` from 1 to 100.
+///
+/// The color is stored as full range YCbCr without chroma subsampling. An
+/// alpha channel is only stored if the image has transparent pixels.
+pub fn encode(image: &RgbaImage, quality: u8) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    ensure!(width > 0 && height > 0, "Image is empty");
+    ensure!(
+        width <= u32::from(u16::MAX) && height <= u32::from(u16::MAX),
+        "Image is too large for AVIF"
+    );
+
+    // smaller images are padded with their last column and row, which the
+    // decoder crops again
+    let frame_width = width.max(MIN_FRAME_SIZE);
+    let frame_height = height.max(MIN_FRAME_SIZE);
+    let pixels = frame_width as usize * frame_height as usize;
+
+    let mut planes = [
+        Vec::with_capacity(pixels),
+        Vec::with_capacity(pixels),
+        Vec::with_capacity(pixels),
+    ];
+    let mut alpha = Vec::with_capacity(pixels);
+    for y in 0..frame_height {
+        for x in 0..frame_width {
+            let [r, g, b, a] = image.get_pixel(x.min(width - 1), y.min(height - 1)).0;
+            let (r, g, b) = (f32::from(r), f32::from(g), f32::from(b));
+
+            // BT.601, like JPEG
+            let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+            planes[0].push(to_u8(luma));
+            planes[1].push(to_u8((b - luma) * 0.564 + 128.0));
+            planes[2].push(to_u8((r - luma) * 0.713 + 128.0));
+            alpha.push(a);
+        }
+    }
+
+    let quantizer = quantizer(quality);
+    let color = encode_frame(
+        (frame_width, frame_height),
+        &planes,
+        ChromaSampling::Cs444,
+        Some(ColorDescription {
+            color_primaries: ColorPrimaries::BT709,
+            transfer_characteristics: TransferCharacteristics::SRGB,
+            matrix_coefficients: MatrixCoefficients::BT601,
+        }),
+        quantizer,
+    )
+    .context("Could not encode color")?;
+
+    let alpha = if alpha.iter().any(|&a| a != u8::MAX) {
+        // rav1e can not encode monochrome frames, so the chroma planes of the
+        // alpha frame are subsampled and left empty
+        let chroma_size = ((frame_width + 1) / 2) as usize * ((frame_height + 1) / 2) as usize;
+        let chroma = vec![128; chroma_size];
+        let frame = encode_frame(
+            (frame_width, frame_height),
+            &[alpha, chroma.clone(), chroma],
+            ChromaSampling::Cs420,
+            None,
+            quantizer,
+        )
+        .context("Could not encode alpha channel")?;
+        Some(frame)
+    } else {
+        None
+    };
+
+    Ok(container(
+        (width, height),
+        (frame_width, frame_height),
+        &color,
+        alpha.as_ref(),
+    ))
+}
+
+fn to_u8(value: f32) -> u8 {
+    value.round().max(0.0).min(255.0) as u8
+}
+
+/// Map a quality from 1 to 100 to the quantizer of rav1e from 255 to 0
+fn quantizer(quality: u8) -> usize {
+    255 - usize::from(quality.min(100)) * 255 / 100
+}
+
+fn encode_frame(
+    (width, height): (u32, u32),
+    planes: &[Vec<u8>],
+    chroma_sampling: ChromaSampling,
+    color_description: Option<ColorDescription>,
+    quantizer: usize,
+) -> Result<Frame> {
+    let mut enc = EncoderConfig::with_speed_preset(SPEED);
+    enc.width = width as usize;
+    enc.height = height as usize;
+    enc.chroma_sampling = chroma_sampling;
+    enc.pixel_range = PixelRange::Full;
+    enc.color_description = color_description;
+    enc.still_picture = true;
+    enc.quantizer = quantizer;
+    enc.min_quantizer = u8::try_from(quantizer).unwrap_or(u8::MAX);
+
+    let mut ctx: Context<u8> = Config { enc, threads: 0 }.new_context()?;
+
+    let mut frame = ctx.new_frame();
+    for (plane, data) in frame.planes.iter_mut().zip(planes) {
+        let stride = plane.cfg.width;
+        plane.copy_from_raw_u8(data, stride, 1);
+    }
+    ctx.send_frame(frame)?;
+    ctx.flush();
+
+    let mut data = Vec::new();
+    loop {
+        match ctx.receive_packet() {
+            Ok(mut packet) => data.append(&mut packet.data),
+            Err(EncoderStatus::Encoded) => continue,
+            Err(EncoderStatus::LimitReached) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(Frame {
+        config: ctx.container_sequence_header(),
+        data,
+    })
+}
+
+/// Write a box of type `kind` with `content`
+fn boxed(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len() + 8);
+    out.extend_from_slice(&(content.len() as u32 + 8).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(content);
+    out
+}
+
+/// Write a full box, which is a box with a version and flags, of type `kind`
+fn full_box(kind: &[u8; 4], version: u8, content: &[u8]) -> Vec<u8> {
+    let mut full = vec![version, 0, 0, 0];
+    full.extend_from_slice(content);
+    boxed(kind, &full)
+}
+
+/// Put the encoded frames of `frame_size` into a HEIF container showing an
+/// image of `size`. Item 1 is the color image and item 2, if there is one,
+/// its alpha channel.
+fn container(
+    size: (u32, u32),
+    frame_size: (u32, u32),
+    color: &Frame,
+    alpha: Option<&Frame>,
+) -> Vec<u8> {
+    let items: Vec<&Frame> = std::iter::once(color).chain(alpha).collect();
+
+    let mut brands = b"avif".to_vec();
+    brands.extend_from_slice(&0u32.to_be_bytes());
+    brands.extend_from_slice(b"avifmif1miaf");
+    let ftyp = boxed(b"ftyp", &brands);
+
+    // the items are located by their offset in the file, which depends on
+    // the size of the meta box. Its size does not depend on the offsets.
+    let meta_size = meta(size, frame_size, &items, 0).len();
+    let data_start = (ftyp.len() + meta_size + 8) as u32;
+    let meta = meta(size, frame_size, &items, data_start);
+
+    let mut mdat = Vec::new();
+    for item in &items {
+        mdat.extend_from_slice(&item.data);
+    }
+
+    let mut out = ftyp;
+    out.extend(meta);
+    out.extend(boxed(b"mdat", &mdat));
+    out
+}
+
+/// Describe the `items` stored one after another from `data_start` on
+fn meta(size: (u32, u32), frame_size: (u32, u32), items: &[&Frame], data_start: u32) -> Vec<u8> {
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(b"pict");
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.push(0);
+
+    // item locations with 4 byte offsets and lengths
+    let mut iloc = vec![0x44, 0];
+    iloc.extend_from_slice(&(items.len() as u16).to_be_bytes());
+    let mut offset = data_start;
+    for (id, item) in (1u16..).zip(items) {
+        iloc.extend_from_slice(&id.to_be_bytes());
+        iloc.extend_from_slice(&0u16.to_be_bytes()); // data reference
+        iloc.extend_from_slice(&1u16.to_be_bytes()); // extents
+        iloc.extend_from_slice(&offset.to_be_bytes());
+        iloc.extend_from_slice(&(item.data.len() as u32).to_be_bytes());
+        offset += item.data.len() as u32;
+    }
+
+    let mut iinf = (items.len() as u16).to_be_bytes().to_vec();
+    for id in 1..=items.len() as u16 {
+        let mut infe = id.to_be_bytes().to_vec();
+        infe.extend_from_slice(&0u16.to_be_bytes()); // protection
+        infe.extend_from_slice(b"av01");
+        infe.push(0); // name
+        iinf.extend(full_box(b"infe", 2, &infe));
+    }
+
+    // properties are referenced by their position, starting at 1, and
+    // essential ones, which decoders must understand, have the high bit set
+    let mut ipco = Vec::new();
+    let mut count = 0;
+    let mut property = |content: Vec<u8>| {
+        ipco.extend(content);
+        count += 1;
+        count
+    };
+    let essential = 0x80;
+
+    let mut ispe = frame_size.0.to_be_bytes().to_vec();
+    ispe.extend_from_slice(&frame_size.1.to_be_bytes());
+    let ispe = property(full_box(b"ispe", 0, &ispe));
+    // the clean aperture crops padded frames, its offsets are fractions
+    // relative to the center of the frame
+    let clap = if size != frame_size {
+        let mut clap = Vec::new();
+        for value in &[
+            size.0 as i32,
+            1,
+            size.1 as i32,
+            1,
+            size.0 as i32 - frame_size.0 as i32,
+            2,
+            size.1 as i32 - frame_size.1 as i32,
+            2,
+        ] {
+            clap.extend_from_slice(&value.to_be_bytes());
+        }
+        Some(property(boxed(b"clap", &clap)) | essential)
+    } else {
+        None
+    };
+
+    let mut color = vec![
+        ispe,
+        property(full_box(b"pixi", 0, &[3, 8, 8, 8])),
+        property(boxed(b"av1C", &items[0].config)) | essential,
+    ];
+    color.extend(clap);
+    let mut associations = vec![(1u16, color)];
+    if let Some(alpha) = items.get(1) {
+        let mut properties = vec![
+            ispe,
+            property(full_box(b"pixi", 0, &[1, 8])),
+            property(boxed(b"av1C", &alpha.config)) | essential,
+            property(full_box(b"auxC", 0, ALPHA_URN)),
+        ];
+        properties.extend(clap);
+        associations.push((2, properties));
+    }
+
+    let mut ipma = (associations.len() as u32).to_be_bytes().to_vec();
+    for (id, properties) in associations {
+        ipma.extend_from_slice(&id.to_be_bytes());
+        ipma.push(properties.len() as u8);
+        ipma.extend(properties);
+    }
+
+    let mut iprp = boxed(b"ipco", &ipco);
+    iprp.extend(full_box(b"ipma", 0, &ipma));
+
+    let mut content = full_box(b"hdlr", 0, &hdlr);
+    content.extend(full_box(b"pitm", 0, &1u16.to_be_bytes()));
+    content.extend(full_box(b"iloc", 0, &iloc));
+    content.extend(full_box(b"iinf", 0, &iinf));
+    if items.len() > 1 {
+        // the alpha item is an auxiliary image of the color item
+        let mut auxl = 2u16.to_be_bytes().to_vec();
+        auxl.extend_from_slice(&1u16.to_be_bytes());
+        auxl.extend_from_slice(&1u16.to_be_bytes());
+        content.extend(full_box(b"iref", 0, &boxed(b"auxl", &auxl)));
+    }
+    content.extend(boxed(b"iprp", &iprp));
+
+    full_box(b"meta", 0, &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::convert::TryInto;
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    /// Get the content of the first box of type `kind` in `data`, which
+    /// starts at a box
+    fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            if &data[pos + 4..pos + 8] == kind {
+                return data.get(pos + 8..pos + size);
+            }
+            pos += size;
+        }
+        None
+    }
+
+    #[test]
+    fn test_encode() {
+        let image = RgbaImage::from_pixel(40, 24, Rgba([200, 40, 40, 255]));
+        let avif = encode(&image, 80).unwrap();
+
+        assert_eq!(&avif[4..12], b"ftypavif");
+        let meta = find_box(&avif, b"meta").unwrap();
+        assert!(!contains(meta, b"auxl"));
+        assert!(!contains(meta, b"clap"));
+
+        // the only item is all of the media data
+        let mdat = find_box(&avif, b"mdat").unwrap();
+        let iloc = find_box(&meta[4..], b"iloc").unwrap();
+        let offset = u32::from_be_bytes(iloc[14..18].try_into().unwrap()) as usize;
+        let length = u32::from_be_bytes(iloc[18..22].try_into().unwrap()) as usize;
+        assert_eq!(offset, avif.len() - mdat.len());
+        assert_eq!(length, mdat.len());
+    }
+
+    #[test]
+    fn test_encode_small() {
+        let mut image = RgbaImage::from_pixel(9, 5, Rgba([0, 0, 255, 255]));
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+        let avif = encode(&image, 50).unwrap();
+
+        let meta = find_box(&avif, b"meta").unwrap();
+        assert!(contains(meta, b"auxl"));
+        assert!(contains(meta, ALPHA_URN));
+        assert!(
+            contains(meta, &[0, 0, 0, 9, 0, 0, 0, 1, 0, 0, 0, 5, 0, 0, 0, 1]),
+            "the padded frame is cropped to the image"
+        );
+    }
+
+    #[test]
+    fn test_quantizer() {
+        assert_eq!(quantizer(100), 0);
+        assert_eq!(quantizer(1), 253);
+        assert_eq!(quantizer(80), 51);
+    }
+}
@@ -1,18 +1,262 @@
+use crate::transforms::{Format, Transform};
 use anyhow::Result;
 use serde::Deserialize;
-use std::{collections::HashSet, fs::File, io::Read, path::Path};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use url::Url;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ImgurConfig {
     #[serde(rename = "client-id")]
     pub client_id: String,
+    /// Base url of the Imgur API, e.g. to test against a mock server
+    #[serde(rename = "api-url", default = "default_imgur_api_url")]
+    pub api_url: String,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_imgur_api_url() -> String {
+    "https://api.imgur.com/3/".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RedditConfig {
+    /// Base url posts are requested from, e.g. to test against a mock server
+    #[serde(rename = "api-url")]
+    pub api_url: String,
+}
+
+impl Default for RedditConfig {
+    fn default() -> Self {
+        RedditConfig {
+            api_url: "https://www.reddit.com/".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Directory uploaded files are stored in
+    pub directory: PathBuf,
+    /// Maximum size of an upload request in bytes
+    #[serde(rename = "max-upload-size")]
+    pub max_upload_size: u64,
+    /// Maximum size of a remote image downloaded by the server in bytes
+    #[serde(rename = "max-download-size")]
+    pub max_download_size: u64,
+    /// Keep EXIF, XMP and other metadata in uploaded and mirrored images
+    /// instead of removing it before the file is stored
+    #[serde(rename = "keep-metadata")]
+    pub keep_metadata: bool,
+    /// Maximum number of files in an uploaded ZIP or tar archive
+    #[serde(rename = "max-archive-entries")]
+    pub max_archive_entries: usize,
+    /// Maximum size of all files extracted from an uploaded archive in bytes
+    #[serde(rename = "max-extracted-size")]
+    pub max_extracted_size: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            directory: PathBuf::from("data"),
+            max_upload_size: 20 * 1024 * 1024,
+            max_download_size: 20 * 1024 * 1024,
+            keep_metadata: false,
+            max_archive_entries: 1000,
+            max_extracted_size: 500 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThumbnailConfig {
+    /// Directory generated thumbnails are cached in.
+    /// Defaults to the `thumbs` directory inside the storage directory.
+    pub directory: Option<PathBuf>,
+    /// Widths in pixels thumbnails are generated for
+    pub widths: Vec<u32>,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            directory: None,
+            widths: vec![320, 640, 1280],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TransformConfig {
+    /// Directory transformed images are cached in.
+    /// Defaults to the `transforms` directory inside the storage directory.
+    pub directory: Option<PathBuf>,
+    /// The only transforms which may be requested
+    pub presets: Vec<Transform>,
+}
+
+impl Default for TransformConfig {
+    fn default() -> Self {
+        let presets = [320, 640, 1280]
+            .iter()
+            .flat_map(|&width| {
+                [Format::Avif, Format::Webp, Format::Jpeg]
+                    .iter()
+                    .map(move |&format| Transform::scale(width, format))
+            })
+            .collect();
+
+        TransformConfig {
+            directory: None,
+            presets,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct DownloadConfig {
+    /// Maximum size of a downloaded album archive in bytes
+    #[serde(rename = "max-size")]
+    pub max_size: u64,
+    /// Seconds fetching the remote images of an archive may take in total
+    pub timeout: u64,
+    /// Maximum number of archives which are sent at the same time
+    #[serde(rename = "max-concurrent")]
+    pub max_concurrent: usize,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            max_size: 2 * 1024 * 1024 * 1024,
+            timeout: 5 * 60,
+            max_concurrent: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct LinkCheckConfig {
+    /// Whether remote images are checked in the background
+    pub enabled: bool,
+    /// Seconds after which an image is checked again
+    pub interval: u64,
+    /// Minimum number of milliseconds between two requests to the same domain
+    #[serde(rename = "domain-delay")]
+    pub domain_delay: u64,
+}
+
+impl Default for LinkCheckConfig {
+    fn default() -> Self {
+        LinkCheckConfig {
+            enabled: true,
+            interval: 24 * 60 * 60,
+            domain_delay: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ScrapeConfig {
+    /// Maximum size of a web page images are picked from in bytes
+    #[serde(rename = "max-page-size")]
+    pub max_page_size: u64,
+    /// Images which the page says are narrower or lower than this many
+    /// pixels are left out, which skips icons and tracking pixels
+    #[serde(rename = "min-size")]
+    pub min_size: u32,
+    /// Maximum number of images offered from one page
+    #[serde(rename = "max-images")]
+    pub max_images: usize,
+}
+
+impl Default for ScrapeConfig {
+    fn default() -> Self {
+        ScrapeConfig {
+            max_page_size: 5 * 1024 * 1024,
+            min_size: 100,
+            max_images: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    /// Whether imported albums are synced with their source periodically
+    pub enabled: bool,
+    /// Seconds after which an imported album is synced again
+    pub interval: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            enabled: false,
+            interval: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct InstancesConfig {
+    /// Hosts of other v instances albums can be imported from, with the
+    /// port if it is not the default one, e.g. `v.example.com`
+    pub hosts: HashSet<String>,
+    /// Maximum size of the manifest of an album in bytes
+    #[serde(rename = "max-manifest-size")]
+    pub max_manifest_size: u64,
+}
+
+impl Default for InstancesConfig {
+    fn default() -> Self {
+        InstancesConfig {
+            hosts: HashSet::new(),
+            max_manifest_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub imgur: ImgurConfig,
+    #[serde(default)]
+    pub reddit: RedditConfig,
     #[serde(rename = "allowed-domains")]
     pub allowed_domains: HashSet<String>,
+    /// Link of this instance as it is reached from outside, e.g.
+    /// `https://v.example.com/`. Exported manifests link stored images with
+    /// it, without it their links are relative.
+    #[serde(default, rename = "public-url")]
+    pub public_url: Option<Url>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub thumbnails: ThumbnailConfig,
+    #[serde(default)]
+    pub transforms: TransformConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
+    #[serde(default, rename = "link-check")]
+    pub link_check: LinkCheckConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub instances: InstancesConfig,
+    #[serde(default)]
+    pub scrape: ScrapeConfig,
 }
 
 impl Config {
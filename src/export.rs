@@ -0,0 +1,391 @@
+use crate::{config::DownloadConfig, models::Image, storage::detect_format};
+use anyhow::{Context, Result};
+use flate2::Crc;
+use log::debug;
+use std::{
+    convert::TryFrom,
+    io::{self, Cursor, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+    },
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// Name of the archive entry listing the images which could not be added
+const ERRORS_NAME: &str = "errors.txt";
+/// Largest piece of an archive passed from the writing to the reading thread
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of pieces which may wait for the reading thread
+const PIPE_CAPACITY: usize = 4;
+
+/// Number of archives being written at the moment
+static ACTIVE_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Write the `images` of an album to a ZIP archive in `out`, in album order.
+///
+/// The archive is written as it is built, so `out` can be sent to the client
+/// right away. The data of each image is returned by `source`. Remote images
+/// are only fetched until the timeout of `config` has passed, and images are
+/// left out once the archive would grow larger than its maximum size. Images
+/// which could not be added are listed in `errors.txt`.
+pub fn write_zip<W, F>(
+    out: W,
+    images: &[Image],
+    config: &DownloadConfig,
+    mut source: F,
+) -> Result<W>
+where
+    W: Write,
+    F: FnMut(&Image) -> Result<Vec<u8>>,
+{
+    let mut zip = ZipStream::new(out);
+
+    let deadline = Instant::now() + Duration::from_secs(config.timeout);
+    let width = index_width(images);
+    let mut size = 0;
+    let mut errors = Vec::new();
+
+    for image in images {
+        if image.file.is_none() && Instant::now() > deadline {
+            errors.push(format!("{}: Timed out", image.url));
+            continue;
+        }
+
+        let data = match source(image) {
+            Ok(data) => data,
+            Err(err) => {
+                debug!("Could not add {} to archive: {:#}", image.url, err);
+                errors.push(format!("{}: {:#}", image.url, err));
+                continue;
+            }
+        };
+
+        size += data.len() as u64;
+        if size > config.max_size || !zip.has_room_for(&data) {
+            errors.push(format!("{}: Archive is too large", image.url));
+            size -= data.len() as u64;
+            continue;
+        }
+
+        let name = entry_name(image, &data, width);
+        zip.add(&name, &data)?;
+    }
+
+    if !errors.is_empty() {
+        zip.add(ERRORS_NAME, errors.join("\n").as_bytes())?;
+    }
+
+    zip.finish().context("Could not finish archive")
+}
+
+/// Permission to write an archive, which is given back when it is dropped
+#[derive(Debug)]
+pub struct DownloadPermit(());
+
+impl DownloadPermit {
+    /// Get a permit unless `max` archives are being written already
+    pub fn acquire(max: usize) -> Option<Self> {
+        ACTIVE_DOWNLOADS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < max).then(|| active + 1)
+            })
+            .ok()
+            .map(|_| DownloadPermit(()))
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        ACTIVE_DOWNLOADS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Create a pipe to pass an archive from the thread writing it to the
+/// thread sending it, holding only a few chunks in memory
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let (sender, receiver) = sync_channel(PIPE_CAPACITY);
+
+    (
+        PipeReader {
+            receiver,
+            chunk: Cursor::new(Vec::new()),
+            finished: false,
+        },
+        PipeWriter { sender },
+    )
+}
+
+/// Reading end of a [`pipe`]
+#[derive(Debug)]
+pub struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Cursor<Vec<u8>>,
+    finished: bool,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() || self.finished {
+                return Ok(read);
+            }
+
+            match self.receiver.recv() {
+                // an empty chunk marks the end of the archive
+                Ok(chunk) if chunk.is_empty() => self.finished = true,
+                Ok(chunk) => self.chunk = Cursor::new(chunk),
+                // the writer stopped before finishing the archive, which
+                // must not look like a complete response
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Archive was not finished",
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Writing end of a [`pipe`]
+#[derive(Debug)]
+pub struct PipeWriter {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl PipeWriter {
+    /// Tell the reader that everything was written
+    pub fn close(self) -> io::Result<()> {
+        self.send(Vec::new())
+    }
+
+    fn send(&self, chunk: Vec<u8>) -> io::Result<()> {
+        self.sender
+            .send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Reader is gone"))
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(CHUNK_SIZE);
+        self.send(buf[..len].to_vec())?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes a ZIP archive of uncompressed files front to back, without
+/// seeking. The data of each file is known before it is written, so its
+/// checksum and size go into its header right away.
+///
+/// Archives are limited to 65535 files and 4 GiB, as ZIP64 is not written.
+struct ZipStream<W> {
+    out: W,
+    /// Number of bytes written so far
+    offset: u64,
+    /// Central directory records of the files written so far
+    directory: Vec<u8>,
+    files: u16,
+}
+
+impl<W: Write> ZipStream<W> {
+    fn new(out: W) -> Self {
+        ZipStream {
+            out,
+            offset: 0,
+            directory: Vec::new(),
+            files: 0,
+        }
+    }
+
+    /// Returns true if a file of the size of `data` and the end of the
+    /// archive still fit into the limits of the format
+    fn has_room_for(&self, data: &[u8]) -> bool {
+        // generous room for the headers of this file and the central directory
+        let headers = 64 * 1024 + self.directory.len() as u64 * 2;
+        self.files < u16::MAX - 1
+            && self.offset + data.len() as u64 + headers <= u64::from(u32::MAX)
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut crc = Crc::new();
+        crc.update(data);
+
+        let name = name.as_bytes();
+        let size = u32::try_from(data.len()).context("File is too large")?;
+        let offset = u32::try_from(self.offset).context("Archive is too large")?;
+
+        // local file header
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        common_header(&mut header, crc.sum(), size, name.len() as u16);
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        header.extend_from_slice(name);
+
+        // central directory record
+        let directory = &mut self.directory;
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&VERSION.to_le_bytes()); // version made by
+        common_header(directory, crc.sum(), size, name.len() as u16);
+        directory.extend_from_slice(&[0; 8]); // extra, comment, disk, internal attributes
+        directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name);
+
+        self.out.write_all(&header)?;
+        self.out.write_all(data)?;
+        self.offset += (header.len() + data.len()) as u64;
+        self.files += 1;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        let offset = u32::try_from(self.offset).context("Archive is too large")?;
+        let size = u32::try_from(self.directory.len()).context("Archive is too large")?;
+
+        self.out.write_all(&self.directory)?;
+
+        // end of central directory record
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // disk numbers
+        end.extend_from_slice(&self.files.to_le_bytes());
+        end.extend_from_slice(&self.files.to_le_bytes());
+        end.extend_from_slice(&size.to_le_bytes());
+        end.extend_from_slice(&offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        self.out.write_all(&end)?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+/// ZIP version 2.0, which is needed for directories and nothing newer
+const VERSION: u16 = 20;
+
+/// Append the fields which local file headers and central directory records
+/// share, from the version needed to the file name length
+fn common_header(buf: &mut Vec<u8>, crc: u32, size: u32, name_len: u16) {
+    buf.extend_from_slice(&VERSION.to_le_bytes()); // version needed
+    buf.extend_from_slice(&0x0800u16.to_le_bytes()); // flags: UTF-8 names
+    buf.extend_from_slice(&0u16.to_le_bytes()); // stored without compression
+    buf.extend_from_slice(&0u16.to_le_bytes()); // modification time 00:00
+    buf.extend_from_slice(&0x0021u16.to_le_bytes()); // modification date 1980-01-01
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes()); // compressed size
+    buf.extend_from_slice(&size.to_le_bytes()); // uncompressed size
+    buf.extend_from_slice(&name_len.to_le_bytes());
+}
+
+/// Number of digits of the largest index, so names sort in album order
+fn index_width(images: &[Image]) -> usize {
+    let max = images.iter().map(|image| image.index).max().unwrap_or(0);
+    max.to_string().len()
+}
+
+/// Name of the archive entry of `image`, e.g. `007-<token>.png`
+fn entry_name(image: &Image, data: &[u8], width: usize) -> String {
+    let extension = detect_format(data)
+        .map(String::from)
+        .or_else(|| url_extension(&image.url))
+        .unwrap_or_else(|| "bin".to_string());
+
+    format!(
+        "{:0width$}-{}.{}",
+        image.index,
+        image.token,
+        extension,
+        width = width
+    )
+}
+
+fn url_extension(url: &str) -> Option<String> {
+    let url: Url = url.parse().ok()?;
+    let name = url.path_segments()?.last()?;
+    let extension = &name[name.rfind('.')? + 1..];
+
+    (!extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphanumeric()))
+        .then(|| extension.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use zip::ZipArchive;
+
+    #[test]
+    fn test_zip_stream() {
+        let mut zip = ZipStream::new(Vec::new());
+        zip.add("1-a.png", b"first").unwrap();
+        zip.add("2-\u{e4}.txt", b"").unwrap();
+        let data = zip.finish().unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        let mut content = String::new();
+        let mut file = archive.by_index(0).unwrap();
+        assert_eq!(file.name(), "1-a.png");
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "first");
+        drop(file);
+
+        assert_eq!(archive.by_index(1).unwrap().name(), "2-\u{e4}.txt");
+    }
+
+    #[test]
+    fn test_pipe() {
+        let (mut reader, mut writer) = pipe();
+        let data = vec![7; CHUNK_SIZE * 3 + 5];
+
+        let written = data.clone();
+        thread::spawn(move || {
+            writer.write_all(&written).unwrap();
+            writer.close().unwrap();
+        });
+
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        // a writer which stops early is an error, not the end of the data
+        let (mut reader, mut writer) = pipe();
+        writer.write_all(b"partial").unwrap();
+        drop(writer);
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_download_permit() {
+        let first = DownloadPermit::acquire(1).unwrap();
+        assert!(DownloadPermit::acquire(1).is_none());
+        drop(first);
+        assert!(DownloadPermit::acquire(1).is_some());
+    }
+
+    #[test]
+    fn test_url_extension() {
+        assert_eq!(
+            url_extension("https://i.imgur.com/abc.PNG"),
+            Some("png".to_string())
+        );
+        assert_eq!(url_extension("https://i.imgur.com/abc"), None);
+        assert_eq!(url_extension("https://i.imgur.com/a.b/c"), None);
+        assert_eq!(url_extension("https://i.imgur.com/abc.p%20g"), None);
+    }
+}
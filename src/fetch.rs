@@ -0,0 +1,424 @@
+use anyhow::{bail, ensure, Context, Result};
+use lazy_static::lazy_static;
+use reqwest::{
+    blocking::{Client, Response},
+    header::{
+        HeaderName, CONNECTION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE,
+        RETRY_AFTER,
+    },
+    redirect::Policy,
+    StatusCode,
+};
+use std::{
+    io::Read,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+use url::Url;
+
+lazy_static! {
+    static ref CLIENT: Client = Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(30))
+        .build()
+        .expect("valid http client");
+    /// Client which does not follow redirects, so their targets can be checked
+    static ref PAGE_CLIENT: Client = Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(30))
+        .redirect(Policy::none())
+        .build()
+        .expect("valid http client");
+}
+
+/// Maximum number of redirects followed when downloading a public resource
+const MAX_REDIRECTS: usize = 10;
+
+/// Shared http client for requests to remote resources
+pub fn client() -> &'static Client {
+    &CLIENT
+}
+
+/// Download the resource at `url`, failing if it is larger than `max_size` bytes
+pub fn download(url: &Url, max_size: u64) -> Result<Vec<u8>> {
+    let resp = client()
+        .get(url.as_str())
+        .send()
+        .with_context(|| format!("Could not download {}", url))?;
+
+    read_body(resp, url, max_size)
+}
+
+/// Download the resource at `url` like `download`, but only from hosts on the
+/// public internet.
+///
+/// Links entered by users could otherwise reach services in the network of
+/// the server. Redirects are followed one by one, so their targets are
+/// checked as well.
+pub fn download_public(url: &Url, max_size: u64) -> Result<Vec<u8>> {
+    let mut url = url.clone();
+
+    for _ in 0..=MAX_REDIRECTS {
+        check_public(&url)?;
+
+        let resp = PAGE_CLIENT
+            .get(url.as_str())
+            .send()
+            .with_context(|| format!("Could not download {}", url))?;
+
+        let location = header(&resp, LOCATION).filter(|_| resp.status().is_redirection());
+        match location {
+            Some(location) => {
+                url = url
+                    .join(&location)
+                    .with_context(|| format!("{} redirects to an invalid link", url))?;
+                ensure!(
+                    matches!(url.scheme(), "http" | "https"),
+                    format!("Invalid scheme: {}", url)
+                );
+            }
+            None => return read_body(resp, &url, max_size),
+        }
+    }
+
+    bail!("{} redirects too often", url)
+}
+
+/// Fail if the host of `url` resolves to an address which is not on the public
+/// internet
+fn check_public(url: &Url) -> Result<()> {
+    let addrs = url
+        .socket_addrs(|| None)
+        .with_context(|| format!("Could not resolve {}", url))?;
+
+    ensure!(
+        addrs.iter().all(|addr| is_public(addr.ip())),
+        format!("{} is not on the public internet", url)
+    );
+
+    Ok(())
+}
+
+/// Returns false for loopback, private, link-local and other addresses which
+/// are not reachable from the internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // "this network", 0.0.0.0/8
+                || first == 0
+                // shared address space of carrier-grade NATs, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local, fc00::/7
+                || first & 0xfe00 == 0xfc00
+                // link-local, fe80::/10
+                || first & 0xffc0 == 0xfe80)
+                && ip.to_ipv4().map_or(true, |ip| is_public(IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// Read the body of a successful response, failing if it is larger than
+/// `max_size` bytes
+fn read_body(resp: Response, url: &Url, max_size: u64) -> Result<Vec<u8>> {
+    ensure!(
+        resp.status().is_success(),
+        format!("Could not download {}: {}", url, resp.status())
+    );
+
+    if let Some(len) = resp.content_length() {
+        ensure!(
+            len <= max_size,
+            format!("{} is larger than {} bytes", url, max_size)
+        );
+    }
+
+    let mut data = Vec::new();
+    resp.take(max_size + 1)
+        .read_to_end(&mut data)
+        .with_context(|| format!("Could not download {}", url))?;
+
+    ensure!(
+        data.len() as u64 <= max_size,
+        format!("{} is larger than {} bytes", url, max_size)
+    );
+
+    Ok(data)
+}
+
+/// Download at most the first `len` bytes of the resource at `url`
+pub fn download_prefix(url: &Url, len: u64) -> Result<Vec<u8>> {
+    let resp = client()
+        .get(url.as_str())
+        .header(RANGE, format!("bytes=0-{}", len.saturating_sub(1)))
+        .send()
+        .with_context(|| format!("Could not download {}", url))?;
+
+    ensure!(
+        resp.status().is_success(),
+        format!("Could not download {}: {}", url, resp.status())
+    );
+
+    // servers ignoring the range send the whole resource, which is cut off
+    let mut data = Vec::new();
+    resp.take(len)
+        .read_to_end(&mut data)
+        .with_context(|| format!("Could not download {}", url))?;
+
+    Ok(data)
+}
+
+/// The type and size a remote resource claims to have
+#[derive(Debug)]
+pub struct Probe {
+    pub content_type: Option<String>,
+    pub size: Option<u64>,
+}
+
+impl Probe {
+    /// The content type without parameters, e.g. `image/png`
+    pub fn essence(&self) -> Option<String> {
+        self.content_type.as_ref().map(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase()
+        })
+    }
+
+    /// Returns true if the resource is an image or a video
+    pub fn is_media(&self) -> bool {
+        match self.essence() {
+            Some(essence) => essence.starts_with("image/") || essence.starts_with("video/"),
+            None => false,
+        }
+    }
+}
+
+/// Ask for the type and size of the resource at `url` without downloading it.
+///
+/// Servers which do not answer `HEAD` requests are asked for the first byte instead.
+pub fn probe(url: &Url) -> Result<Probe> {
+    let resp = client()
+        .head(url.as_str())
+        // some servers, e.g. other v instances, silently drop the connection
+        // after a HEAD request, which fails the next request reusing it
+        .header(CONNECTION, "close")
+        .send()
+        .with_context(|| format!("Could not reach {}", url))?;
+
+    if resp.status().is_success() {
+        return Ok(Probe {
+            content_type: header(&resp, CONTENT_TYPE),
+            size: header(&resp, CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+        });
+    }
+
+    let resp = client()
+        .get(url.as_str())
+        .header(RANGE, "bytes=0-0")
+        .send()
+        .with_context(|| format!("Could not reach {}", url))?;
+
+    ensure!(
+        resp.status().is_success(),
+        format!("{} responded with {}", url, resp.status())
+    );
+
+    let size = if resp.status() == StatusCode::PARTIAL_CONTENT {
+        // e.g. `bytes 0-0/1234`
+        header(&resp, CONTENT_RANGE)
+            .and_then(|range| range.rsplit('/').next().and_then(|len| len.parse().ok()))
+    } else {
+        header(&resp, CONTENT_LENGTH).and_then(|len| len.parse().ok())
+    };
+
+    Ok(Probe {
+        content_type: header(&resp, CONTENT_TYPE),
+        size,
+    })
+}
+
+/// How a server answered a link check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkCheck {
+    pub status: StatusCode,
+    /// Time the server asked to wait for before the next request, from the
+    /// `Retry-After` header
+    pub retry_after: Option<Duration>,
+}
+
+/// Get the HTTP status the resource at `url` is served with.
+///
+/// Servers which do not answer `HEAD` requests are asked for the first byte
+/// instead.
+pub fn check(url: &Url) -> Result<LinkCheck> {
+    let resp = client()
+        .head(url.as_str())
+        .send()
+        .with_context(|| format!("Could not reach {}", url))?;
+
+    if resp.status().is_success() {
+        return Ok(LinkCheck {
+            status: resp.status(),
+            retry_after: None,
+        });
+    }
+
+    let resp = client()
+        .get(url.as_str())
+        .header(RANGE, "bytes=0-0")
+        .send()
+        .with_context(|| format!("Could not reach {}", url))?;
+
+    Ok(LinkCheck {
+        status: resp.status(),
+        retry_after: header(&resp, RETRY_AFTER).and_then(|value| retry_after(&value)),
+    })
+}
+
+/// Parse a `Retry-After` header, which is either a number of seconds or a date
+fn retry_after(value: &str) -> Option<Duration> {
+    match value.trim().parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value.trim())
+            .ok()
+            .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default()),
+    }
+}
+
+fn header(resp: &Response, name: HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    /// Answer one request per response on a local port
+    fn serve(responses: Vec<&'static str>) -> Url {
+        mock::serve(responses).join("image.png").unwrap()
+    }
+
+    #[test]
+    fn test_probe_head() {
+        let url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 1234\r\n\r\n",
+        ]);
+
+        let probe = probe(&url).unwrap();
+        assert!(probe.is_media());
+        assert_eq!(probe.size, Some(1234));
+    }
+
+    #[test]
+    fn test_probe_range() {
+        let url = serve(vec![
+            "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 206 Partial Content\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Content-Range: bytes 0-0/4321\r\nContent-Length: 1\r\n\r\n<",
+        ]);
+
+        let probe = probe(&url).unwrap();
+        assert!(!probe.is_media());
+        assert_eq!(probe.size, Some(4321));
+    }
+
+    #[test]
+    fn test_download_prefix() {
+        let url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789",
+        ]);
+
+        assert_eq!(download_prefix(&url, 4).unwrap(), b"0123");
+    }
+
+    #[test]
+    fn test_check() {
+        let url = serve(vec![
+            "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        ]);
+
+        assert_eq!(
+            check(&url).unwrap(),
+            LinkCheck {
+                status: StatusCode::NOT_FOUND,
+                retry_after: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_retry_after() {
+        let url = serve(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 120\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 120\r\nContent-Length: 0\r\n\r\n",
+        ]);
+
+        assert_eq!(
+            check(&url).unwrap(),
+            LinkCheck {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                retry_after: Some(Duration::from_secs(120)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_download_public() {
+        let url = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"]);
+
+        assert!(download_public(&url, 10).is_err());
+        assert_eq!(download(&url, 10).unwrap(), b"ok");
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in &["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(retry_after(" 30 "), Some(Duration::from_secs(30)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(retry_after("soon"), None);
+    }
+}
@@ -1,30 +1,143 @@
-use crate::{config::Config, imgur::get_album_images, models::Album, models::Image, VDbConn};
-use anyhow::Result;
-use diesel::{OptionalExtension, PgConnection, RunQueryDsl};
+use crate::{
+    config::Config,
+    export::{self, write_zip, DownloadPermit, PipeReader},
+    fetch::{self, probe, Probe},
+    import::Importers,
+    jobs::JobStatus,
+    manifest::Manifest,
+    media::{resolve_gifv, MediaKind},
+    metadata,
+    models::Album,
+    models::{Image, ImageMetadata, ImportJob, NewLinksJob, LINK_UNREACHABLE, MAX_TITLE_LENGTH},
+    phash::{clusters, is_similar},
+    scrape,
+    storage::{detect_format, file_name, file_url, Storage},
+    thumbnails::Thumbnails,
+    unpack::{self, is_archive, Limits},
+    upload::MultipartForm,
+    VDbConn,
+};
+use anyhow::{anyhow, Result};
+use diesel::{Connection, OptionalExtension, PgConnection, RunQueryDsl};
+use log::warn;
 use rocket::{
     data::FromData,
     http::Cookie,
-    http::{Cookies, RawStr, Status},
-    request::{Form, FormDataError, FormError},
-    response::{status::Created, status::Custom, Redirect},
-    State,
+    http::{Cookies, Header, RawStr, Status},
+    request::{Form, FormDataError, FormError, FormItems, FormParseError, FromForm, FromFormValue},
+    response::{status::Created, status::Custom, Redirect, Stream},
+    Data, State,
 };
-use rocket_contrib::templates::Template;
+use rocket_contrib::{json::Json, templates::Template};
 use serde::Serialize;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fs::File,
+    io::{BufReader, Read},
+    thread,
+    time::SystemTime,
+};
 use url::Url;
 
+/// Number of images shown per page if no limit is requested
+const DEFAULT_PAGE_LIMIT: u32 = 50;
+/// Upper bound for the requested number of images per page
+const MAX_PAGE_LIMIT: u32 = 500;
+/// Seconds between reloads of the status page of a running import
+const IMPORT_STATUS_REFRESH: u32 = 2;
+/// Maximum number of image links which can be pasted at once
+const MAX_PASTED_LINKS: usize = 100;
+/// Number of image links which are added while the request waits. The
+/// images of longer lists are added by the import worker.
+const MAX_DIRECT_IMAGES: usize = 5;
+
 #[derive(Debug, Serialize)]
 pub struct AlbumContext<'a> {
     pub title: &'a Option<String>,
     pub token: &'a str,
-    pub images: &'a Vec<String>,
+    pub images: &'a Vec<ImageContext<'a>>,
+    pub pagination: &'a Pagination,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Pagination {
+    pub page: u32,
+    pub limit: u32,
+    pub prev: Option<u32>,
+    pub next: Option<u32>,
+}
+
+impl Pagination {
+    /// Validate the `page` and `limit` query parameters. Pages start at 1.
+    fn new(page: Option<u32>, limit: Option<u32>) -> Result<Self, Custom<String>> {
+        let page = page.unwrap_or(1);
+        let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+
+        if page == 0 {
+            return Err(Custom(
+                Status::BadRequest,
+                "Invalid query: page must be at least 1".to_string(),
+            ));
+        }
+
+        if limit == 0 || limit > MAX_PAGE_LIMIT {
+            return Err(Custom(
+                Status::BadRequest,
+                format!(
+                    "Invalid query: limit must be between 1 and {}",
+                    MAX_PAGE_LIMIT
+                ),
+            ));
+        }
+
+        Ok(Pagination {
+            page,
+            limit,
+            prev: if page > 1 { Some(page - 1) } else { None },
+            next: None,
+        })
+    }
+
+    /// The page containing the image at `index`, validating the `limit`
+    /// query parameter
+    fn containing(index: i32, limit: Option<u32>) -> Result<Self, Custom<String>> {
+        let page = Pagination::new(None, limit)?.page_of(index);
+        Pagination::new(Some(page), limit)
+    }
+
+    /// The page with this limit which contains the image at `index`
+    fn page_of(&self, index: i32) -> u32 {
+        index.max(0) as u32 / self.limit + 1
+    }
+
+    /// First image index on this page
+    fn start(&self) -> i32 {
+        ((self.page - 1) as u64 * self.limit as u64).min(i32::MAX as u64) as i32
+    }
+
+    /// First image index on the next page
+    fn end(&self) -> i32 {
+        (self.page as u64 * self.limit as u64).min(i32::MAX as u64) as i32
+    }
 }
 
-#[get("/<token>")]
-pub fn get(conn: VDbConn, token: &RawStr) -> Result<Template, Custom<String>> {
+#[get("/<token>?<page>&<limit>")]
+pub fn get(
+    conn: VDbConn,
+    token: &RawStr,
+    page: Option<u32>,
+    limit: Option<u32>,
+    thumbnails: State<Thumbnails>,
+) -> Result<Template, Custom<String>> {
+    let mut pagination = Pagination::new(page, limit)?;
     let album = get_album(&conn, token)?;
-    let images = get_image_urls(&conn, &album)?;
+    let images = get_images_page(&conn, &album, &mut pagination)?;
+    let thumbnails = thumbnails.inner();
+    let images = images
+        .iter()
+        .map(|image| ImageContext::new(image, thumbnails))
+        .collect();
 
     Ok(Template::render(
         "album/show",
@@ -32,6 +145,7 @@ pub fn get(conn: VDbConn, token: &RawStr) -> Result<Template, Custom<String>> {
             title: &album.title,
             token: &album.token,
             images: &images,
+            pagination: &pagination,
         },
     ))
 }
@@ -90,7 +204,8 @@ pub fn post_auth(
 
 #[derive(Debug, FromForm)]
 pub struct EditAlbumForm {
-    index: u16,
+    index: u32,
+    limit: Option<u32>,
     url: String,
     deletion_token: String,
     method: String,
@@ -103,44 +218,309 @@ pub struct AlbumEditContext<'a> {
     pub deletion_token: &'a str,
     pub images: &'a Vec<ImageContext<'a>>,
     pub image_count: usize,
+    pub total_size: String,
+    pub pagination: &'a Pagination,
+    pub warnings: &'a [String],
+    /// Link of the album this album is synced with
+    pub source: &'a Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ImageContext<'a> {
     pub token: &'a str,
     pub url: &'a str,
+    pub index: i32,
+    pub source: &'a Option<String>,
+    pub caption: &'a Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub video: bool,
+    /// Inline style showing a preview until the image is loaded
+    pub placeholder: Option<String>,
+    pub broken: bool,
+    pub link_status: Option<i32>,
+    pub removed_upstream: bool,
+    pub thumbnail: String,
+    pub srcset: String,
 }
 
-impl<'a> From<&'a Image> for ImageContext<'a> {
-    fn from(image: &'a Image) -> Self {
+impl<'a> ImageContext<'a> {
+    fn new(image: &'a Image, thumbnails: &Thumbnails) -> Self {
+        let thumbnail_url = |width| format!("/thumb/{}/{}", image.token, width);
+
         ImageContext {
             token: &image.token,
             url: &image.url,
+            index: image.index,
+            source: &image.source,
+            caption: &image.caption,
+            width: image.width,
+            height: image.height,
+            video: image.kind == MediaKind::Video,
+            placeholder: placeholder_style(image),
+            broken: image.is_broken(),
+            link_status: image.link_status,
+            removed_upstream: image.removed_upstream,
+            thumbnail: thumbnails
+                .widths()
+                .last()
+                .map(thumbnail_url)
+                .unwrap_or_else(|| image.url.clone()),
+            srcset: thumbnails
+                .widths()
+                .iter()
+                .map(|width| format!("{} {}w", thumbnail_url(width), width))
+                .collect::<Vec<_>>()
+                .join(", "),
         }
     }
 }
 
-#[get("/<token>/edit")]
+/// Show the dominant color and blurred preview of `image` as its background
+fn placeholder_style(image: &Image) -> Option<String> {
+    let mut style = Vec::new();
+
+    if let Some(color) = &image.color {
+        style.push(format!("background-color: {}", color));
+    }
+    if let Some(url) = &image.placeholder {
+        style.push(format!(
+            "background-image: url({}); background-size: 100% 100%",
+            url
+        ));
+    }
+
+    if style.is_empty() {
+        None
+    } else {
+        Some(style.join("; "))
+    }
+}
+
+#[get("/<token>/edit?<page>&<limit>")]
 pub fn get_edit(
     conn: VDbConn,
     token: &RawStr,
+    page: Option<u32>,
+    limit: Option<u32>,
     mut cookies: Cookies,
+    thumbnails: State<Thumbnails>,
 ) -> Result<Template, Custom<String>> {
+    let mut pagination = Pagination::new(page, limit)?;
     let album = get_album(&conn, token)?;
 
     check_deletion_token_cookie(&album, &mut cookies)?;
 
-    let images = get_images(&conn, &album)?;
-    let images = images.iter().map(|image| image.into()).collect();
+    render_edit(&conn, &album, &thumbnails, &mut pagination, &[])
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrokenLinksContext<'a> {
+    pub title: &'a Option<String>,
+    pub token: &'a str,
+    pub images: Vec<BrokenLinkContext<'a>>,
+    /// Images per page of the edit page the links point to
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrokenLinkContext<'a> {
+    pub url: &'a str,
+    pub index: i32,
+    /// Edit page showing the image
+    pub page: u32,
+    pub status: String,
+    pub checked: String,
+}
+
+#[get("/<token>/broken?<limit>")]
+pub fn get_broken(
+    conn: VDbConn,
+    token: &RawStr,
+    limit: Option<u32>,
+    mut cookies: Cookies,
+) -> Result<Template, Custom<String>> {
+    let pagination = Pagination::new(None, limit)?;
+    let album = get_album(&conn, token)?;
+
+    check_deletion_token_cookie(&album, &mut cookies)?;
+
+    let images = album
+        .broken_images(&conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    let images = images
+        .iter()
+        .map(|image| BrokenLinkContext {
+            url: &image.url,
+            index: image.index,
+            page: pagination.page_of(image.index),
+            status: match image.link_status {
+                Some(LINK_UNREACHABLE) | None => "unreachable".to_string(),
+                Some(status) => status.to_string(),
+            },
+            checked: image
+                .link_checked_at
+                .map(format_age)
+                .unwrap_or_else(|| "never".to_string()),
+        })
+        .collect();
 
     Ok(Template::render(
-        "album/edit",
-        AlbumEditContext {
+        "album/broken",
+        BrokenLinksContext {
             title: &album.title,
             token: &album.token,
-            deletion_token: &album.deletion_token,
-            images: &images,
-            image_count: images.len(),
+            images,
+            limit: pagination.limit,
+        },
+    ))
+}
+
+#[derive(Debug, Responder)]
+#[response(content_type = "application/zip")]
+pub struct ZipDownload {
+    stream: Stream<PipeReader>,
+    disposition: Header<'static>,
+}
+
+#[get("/<token>/download")]
+pub fn download(
+    conn: VDbConn,
+    token: &RawStr,
+    config: State<Config>,
+    storage: State<Storage>,
+) -> Result<ZipDownload, Custom<String>> {
+    let album = get_album(&conn, token)?;
+    let images = album
+        .get_images(&conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+
+    // the archive is sent while it is written, which takes a thread each
+    let permit = DownloadPermit::acquire(config.download.max_concurrent).ok_or_else(|| {
+        Custom(
+            Status::ServiceUnavailable,
+            "Too many downloads at once, try again later".to_string(),
+        )
+    })?;
+
+    let (reader, writer) = export::pipe();
+    let storage = storage.inner().clone();
+    let config = config.inner().clone();
+    let album_token = album.token.clone();
+
+    thread::Builder::new()
+        .name("download".into())
+        .spawn(move || {
+            let _permit = permit;
+            let source = |image: &Image| match &image.file {
+                Some(file) => storage.read(file),
+                None => {
+                    let url = validate_url(&config.allowed_domains, &image.url)
+                        .map_err(|Custom(_, err)| anyhow!(err))?;
+                    fetch::download(&url, config.storage.max_download_size)
+                }
+            };
+
+            let written = write_zip(writer, &images, &config.download, source)
+                .and_then(|writer| Ok(writer.close()?));
+            if let Err(err) = written {
+                warn!("Could not send archive of album {}: {:#}", album_token, err);
+            }
+        })
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+
+    let name = album
+        .title
+        .as_deref()
+        .map(archive_name)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| album.token.clone());
+
+    Ok(ZipDownload {
+        stream: Stream::from(reader),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.zip\"", name),
+        ),
+    })
+}
+
+/// Describe the album in the manifest format, which can be imported with
+/// `import_manifest`
+#[get("/<token>/manifest.json")]
+pub fn manifest(
+    conn: VDbConn,
+    token: &RawStr,
+    config: State<Config>,
+) -> Result<Json<Manifest>, Custom<String>> {
+    let album = get_album(&conn, token)?;
+    let images = album
+        .get_images(&conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+
+    Ok(Json(Manifest::new(
+        &album,
+        &images,
+        config.public_url.as_ref(),
+    )))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DuplicatesContext<'a> {
+    pub title: &'a Option<String>,
+    pub token: &'a str,
+    pub clusters: Vec<Vec<DuplicateContext<'a>>>,
+    /// Images per page of the edit page the links point to
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DuplicateContext<'a> {
+    pub image: ImageContext<'a>,
+    /// Edit page showing the image
+    pub page: u32,
+}
+
+#[get("/<token>/duplicates?<limit>")]
+pub fn get_duplicates(
+    conn: VDbConn,
+    token: &RawStr,
+    limit: Option<u32>,
+    mut cookies: Cookies,
+    thumbnails: State<Thumbnails>,
+) -> Result<Template, Custom<String>> {
+    let pagination = Pagination::new(None, limit)?;
+    let album = get_album(&conn, token)?;
+
+    check_deletion_token_cookie(&album, &mut cookies)?;
+
+    let images = album
+        .hashed_images(&conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    let hashes: Vec<_> = images
+        .iter()
+        .filter_map(|image| Some((image, image.phash?)))
+        .collect();
+    let clusters = clusters(&hashes)
+        .into_iter()
+        .map(|cluster| {
+            cluster
+                .into_iter()
+                .map(|image| DuplicateContext {
+                    image: ImageContext::new(image, &thumbnails),
+                    page: pagination.page_of(image.index),
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(Template::render(
+        "album/duplicates",
+        DuplicatesContext {
+            title: &album.title,
+            token: &album.token,
+            clusters,
+            limit: pagination.limit,
         },
     ))
 }
@@ -150,14 +530,24 @@ pub fn post_edit(
     conn: VDbConn,
     token: &RawStr,
     sink: Result<Form<EditAlbumForm>, FormError>,
+    config: State<Config>,
+    storage: State<Storage>,
+    thumbnails: State<Thumbnails>,
 ) -> Result<Template, Custom<String>> {
     let form_result = parse_form(sink)?;
     let album = get_album(&conn, token)?;
 
     check_deletion_token(&album, &form_result.deletion_token)?;
 
-    match form_result.method.as_str() {
-        "insert" => insert_image(&conn, &album, form_result.into_inner())?,
+    let index = image_index(form_result.index)?;
+    let mut pagination = Pagination::containing(index, form_result.limit)?;
+
+    let warnings = match form_result.method.as_str() {
+        "insert" => insert_image(&conn, &album, index, &form_result.url, &config, &storage)?,
+        "delete" => {
+            delete_image(&conn, &album, index)?;
+            Vec::new()
+        }
         _ => {
             return Err(Custom(
                 Status::BadRequest,
@@ -166,8 +556,69 @@ pub fn post_edit(
         }
     };
 
-    let images = get_images(&conn, &album)?;
-    let images = images.iter().map(|image| image.into()).collect();
+    render_edit(&conn, &album, &thumbnails, &mut pagination, &warnings)
+}
+
+#[post("/<token>/upload", data = "<sink>")]
+pub fn post_upload(
+    conn: VDbConn,
+    token: &RawStr,
+    sink: Result<MultipartForm, Custom<String>>,
+    storage: State<Storage>,
+    thumbnails: State<Thumbnails>,
+) -> Result<Template, Custom<String>> {
+    let mut form = sink?;
+    let album = get_album(&conn, token)?;
+
+    check_deletion_token(&album, form.field("deletion_token")?)?;
+
+    let index = form
+        .field("index")?
+        .parse()
+        .map_err(|err| Custom(Status::BadRequest, format!("Invalid form input: {}", err)))
+        .and_then(image_index)?;
+    let limit =
+        match form.field("limit") {
+            Ok(limit) => Some(limit.parse().map_err(|err| {
+                Custom(Status::BadRequest, format!("Invalid form input: {}", err))
+            })?),
+            Err(_) => None,
+        };
+    let mut pagination = Pagination::containing(index, limit)?;
+    let file = form.take_file("file")?;
+    let filename = file.name().to_string();
+    let (name, metadata) = store_file(&storage, &filename, file.read()?)?;
+
+    make_room(&conn, &album, index)?;
+    let image = album
+        .add_file(&*conn, &name, index)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    save_metadata(&conn, &image, &metadata);
+    let warnings: Vec<_> = duplicate_warning(&conn, &album, &image, metadata.phash)
+        .into_iter()
+        .collect();
+
+    render_edit(&conn, &album, &thumbnails, &mut pagination, &warnings)
+}
+
+fn render_edit(
+    conn: &PgConnection,
+    album: &Album,
+    thumbnails: &Thumbnails,
+    pagination: &mut Pagination,
+    warnings: &[String],
+) -> Result<Template, Custom<String>> {
+    let image_count = album
+        .image_count(conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    let total_size = album
+        .total_size(conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    let images = get_images_page(conn, album, pagination)?;
+    let images = images
+        .iter()
+        .map(|image| ImageContext::new(image, thumbnails))
+        .collect();
 
     Ok(Template::render(
         "album/edit",
@@ -176,7 +627,11 @@ pub fn post_edit(
             token: &album.token,
             deletion_token: &album.deletion_token,
             images: &images,
-            image_count: images.len(),
+            image_count,
+            total_size: format_size(total_size),
+            pagination,
+            warnings,
+            source: &album.source,
         },
     ))
 }
@@ -185,6 +640,7 @@ pub fn post_edit(
 pub struct NewAlbumForm {
     title: String,
     url: String,
+    archive: bool,
 }
 
 #[post("/new", data = "<sink>")]
@@ -192,6 +648,7 @@ pub fn new(
     conn: VDbConn,
     sink: Result<Form<NewAlbumForm>, FormError>,
     config: State<Config>,
+    storage: State<Storage>,
 ) -> Result<Created<Template>, Custom<String>> {
     let form_result = parse_form(sink)?;
 
@@ -201,37 +658,139 @@ pub fn new(
         Some(form_result.title.as_str())
     };
 
-    let album = Album::new(&*conn, title)
-        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    let (links, warnings) = check_lines(&form_result.url, &config)?;
+
+    let album = NewLinksAlbum {
+        title,
+        archive: form_result.archive,
+        page: None,
+    };
+    if links.len() > MAX_DIRECT_IMAGES {
+        return album.queue(&conn, &links, warnings);
+    }
+    album.create(&conn, links, warnings, &config, &storage)
+}
+
+#[post("/upload", data = "<sink>")]
+pub fn upload(
+    conn: VDbConn,
+    sink: Result<MultipartForm, Custom<String>>,
+    config: State<Config>,
+    storage: State<Storage>,
+) -> Result<Created<Template>, Custom<String>> {
+    let mut form = sink?;
+
+    let title = match form.field("title") {
+        Ok("") | Err(_) => None,
+        Ok(title) => Some(title.to_string()),
+    };
+
+    let mut file = form.take_file("file")?;
+    if is_archive(&file.head(unpack::HEAD_SIZE)?) {
+        return upload_archive(
+            &conn,
+            title.as_deref(),
+            file.into_file()?,
+            &config,
+            &storage,
+        );
+    }
 
-    let url = validate_url(&config.allowed_domains, &form_result.url)?;
+    let filename = file.name().to_string();
+    let (name, metadata) = store_file(&storage, &filename, file.read()?)?;
 
-    album
-        .add_image(&*conn, url.as_str(), 0)
+    let album = Album::new(&*conn, title.as_deref(), false)
         .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
 
-    // TODO: show album
-    let mut context = HashMap::new();
-    context.insert("deletion_token", album.deletion_token);
-    context.insert("token", album.token.clone());
-    Ok(Created(
-        format!("/a/{}", album.token),
-        Some(Template::render("album/created", &context)),
-    ))
+    let image = album
+        .add_file(&*conn, &name, 0)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    save_metadata(&conn, &image, &metadata);
+
+    Ok(created(album, Vec::new(), false))
+}
+
+/// Create an album from the images in an uploaded ZIP or tar archive,
+/// ordered by their file names.
+///
+/// The files are stored while they are extracted, so only one is in memory
+/// at a time. The album is only created if all its images could be added.
+fn upload_archive(
+    conn: &PgConnection,
+    title: Option<&str>,
+    archive: File,
+    config: &Config,
+    storage: &Storage,
+) -> Result<Created<Template>, Custom<String>> {
+    let limits = Limits {
+        entries: config.storage.max_archive_entries,
+        entry_size: config.storage.max_upload_size,
+        total_size: config.storage.max_extracted_size,
+    };
+
+    let mut warnings = Vec::new();
+    let mut files = Vec::new();
+    unpack::extract(BufReader::new(archive), limits, |entry| {
+        // skip readmes and the like instead of failing the whole upload
+        if detect_format(&entry.data).is_none() {
+            warnings.push(format!("Skipped {}: not an image or video", entry.name));
+            return Ok(());
+        }
+
+        match store_file(storage, &entry.name, entry.data) {
+            Ok(file) => files.push((entry.name, file)),
+            Err(Custom(_, err)) => warnings.push(format!("Skipped {}: {}", entry.name, err)),
+        }
+        Ok(())
+    })
+    .map_err(|err| Custom(Status::BadRequest, format!("Invalid archive: {:#}", err)))?;
+
+    if files.is_empty() {
+        return Err(Custom(
+            Status::BadRequest,
+            "Archive contains no images".to_string(),
+        ));
+    }
+    files.sort_by(|(a, _), (b, _)| unpack::natural_cmp(a, b));
+
+    // stored files of a failed upload are removed by the garbage collection
+    let (album, hashes) = conn
+        .transaction::<_, anyhow::Error, _>(|| {
+            let album = Album::new(conn, title, false)?;
+
+            let mut hashes = Vec::new();
+            for (index, (_, (name, metadata))) in files.iter().enumerate() {
+                let image = album.add_file(conn, name, index as i32)?;
+                image.set_metadata(conn, metadata)?;
+
+                if let Some(phash) = metadata.phash {
+                    hashes.push((image.index, phash));
+                }
+            }
+
+            Ok((album, hashes))
+        })
+        .map_err(|err| Custom(Status::InternalServerError, format!("{:#}", err)))?;
+
+    warnings.extend(import_warnings(conn, &album, &hashes));
+
+    Ok(created(album, warnings, false))
 }
 
 #[derive(Debug, FromForm)]
 pub struct ImportAlbumForm {
     title: String,
     url: String,
+    archive: bool,
 }
 
 #[post("/import", data = "<sink>")]
 pub fn import(
     conn: VDbConn,
     sink: Result<Form<ImportAlbumForm>, FormError>,
-    config: State<Config>,
-) -> Result<Created<Template>, Custom<String>> {
+    importers: State<Importers>,
+    mut cookies: Cookies,
+) -> Result<Redirect, Custom<String>> {
     let form_result = parse_form(sink)?;
 
     let title = if form_result.title.is_empty() {
@@ -245,67 +804,466 @@ pub fn import(
         .parse()
         .map_err(|err| Custom(Status::BadRequest, format!("Invalid form input: {}", err)))?;
 
-    if url.domain() != Some("imgur.com") {
+    importers
+        .find(&url)
+        .ok_or_else(|| format!("Unsupported album link: {}", url))
+        .and_then(|importer| importer.validate(&url))
+        .map_err(|err| Custom(Status::BadRequest, format!("Invalid form input: {}", err)))?;
+
+    // the images are added by the import worker
+    let album = conn
+        .transaction(|| {
+            let album = Album::new_imported(&*conn, title, form_result.archive, url.as_str())?;
+            ImportJob::new(&*conn, album.id, url.as_str())?;
+            Ok(album)
+        })
+        .map_err(|err: anyhow::Error| Custom(Status::InternalServerError, err.to_string()))?;
+
+    // the status page shows the deletion token to whoever started the import
+    cookies.add_private(Cookie::new(
+        album.token.clone(),
+        album.deletion_token.clone(),
+    ));
+
+    Ok(Redirect::to(format!("/a/{}/import-status", album.token)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportStatusContext<'a> {
+    pub title: &'a Option<String>,
+    pub token: &'a str,
+    pub deletion_token: Option<&'a str>,
+    /// Link of the imported album, or of the page a list of links is from
+    pub url: Option<&'a str>,
+    pub status: JobStatus,
+    pub finished: bool,
+    pub total: Option<i32>,
+    pub processed: i32,
+    pub fetched: i32,
+    pub inserted: i32,
+    pub skipped: i32,
+    pub removed: i32,
+    pub errors: &'a [String],
+    pub warnings: &'a [String],
+    /// Seconds until the page reloads, while the import is not finished
+    pub refresh: Option<u32>,
+}
+
+#[get("/<token>/import-status")]
+pub fn import_status(
+    conn: VDbConn,
+    token: &RawStr,
+    mut cookies: Cookies,
+) -> Result<Template, Custom<String>> {
+    let album = get_album(&conn, token)?;
+    let job = album
+        .latest_import_job(&conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?
+        .ok_or_else(|| Custom(Status::NotFound, "Album was not imported".to_string()))?;
+
+    let is_owner = check_deletion_token_cookie(&album, &mut cookies).is_ok();
+    let finished = job.status.is_finished();
+
+    let context = ImportStatusContext {
+        title: &album.title,
+        token: &album.token,
+        deletion_token: is_owner.then_some(album.deletion_token.as_str()),
+        url: job.url.as_deref(),
+        status: job.status,
+        finished,
+        total: job.total,
+        processed: job.processed(),
+        fetched: job.fetched,
+        inserted: job.inserted,
+        skipped: job.skipped,
+        removed: job.removed,
+        errors: &job.errors,
+        warnings: &job.warnings,
+        refresh: (!finished).then_some(IMPORT_STATUS_REFRESH),
+    };
+
+    Ok(Template::render("album/import_status", &context))
+}
+
+#[derive(Debug, FromForm)]
+pub struct ScrapeForm {
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScrapeContext<'a> {
+    pub page: &'a str,
+    pub title: Option<String>,
+    pub images: Vec<CandidateContext>,
+    /// Number of images which are not on an allowed domain
+    pub not_allowed: usize,
+    /// Number of images which are smaller than the minimum size
+    pub too_small: usize,
+    /// Number of images which were left out because the page has too many
+    pub too_many: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CandidateContext {
+    pub url: String,
+    pub video: bool,
+}
+
+/// Show the images on a web page so the user can pick which ones to add to
+/// a new album
+#[post("/scrape", data = "<sink>")]
+pub fn scrape(
+    sink: Result<Form<ScrapeForm>, FormError>,
+    config: State<Config>,
+) -> Result<Template, Custom<String>> {
+    let form_result = parse_form(sink)?;
+
+    let url: Url = form_result
+        .url
+        .parse()
+        .map_err(|err| Custom(Status::BadRequest, format!("Invalid form input: {}", err)))?;
+    if !matches!(url.scheme(), "http" | "https") {
         return Err(Custom(
             Status::BadRequest,
-            format!("Invalid form input: URL is not allowed: {}", url),
+            format!("Invalid form input: Invalid scheme: {}", url),
         ));
     }
 
-    let mut path_segments = url
-        .path_segments()
-        .ok_or_else(|| "cannot be base")
-        .map_err(|err| {
-            Custom(
-                Status::BadRequest,
-                format!("Could not parse album link: {}", err),
-            )
-        })?;
-    let album_hash = path_segments.nth(1).unwrap();
+    let html = fetch::download_public(&url, config.scrape.max_page_size)
+        .map_err(|err| Custom(Status::BadRequest, format!("Could not get page: {:#}", err)))?;
+    let page = scrape::scrape(&String::from_utf8_lossy(&html), &url);
+
+    let min_size = config.scrape.min_size;
+    let mut context = ScrapeContext {
+        page: url.as_str(),
+        title: page
+            .title
+            .map(|title| title.chars().take(MAX_TITLE_LENGTH).collect()),
+        images: Vec::new(),
+        not_allowed: 0,
+        too_small: 0,
+        too_many: 0,
+    };
 
-    let links = get_album_images(&config.imgur.client_id, album_hash).map_err(|err| {
-        Custom(
-            Status::BadRequest,
-            format!("Could not get album images: {}", err),
-        )
-    })?;
+    for candidate in page.candidates {
+        let url = match validate_url(&config.allowed_domains, candidate.url.as_str()) {
+            Ok(url) => url,
+            Err(_) => {
+                context.not_allowed += 1;
+                continue;
+            }
+        };
+
+        // the size is only known if the page tells
+        if candidate.width.map_or(false, |width| width < min_size)
+            || candidate.height.map_or(false, |height| height < min_size)
+        {
+            context.too_small += 1;
+            continue;
+        }
 
-    let album = Album::new(&*conn, title)
-        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+        if context.images.len() >= config.scrape.max_images {
+            context.too_many += 1;
+            continue;
+        }
 
-    let mut images = Vec::new();
+        context.images.push(CandidateContext {
+            video: MediaKind::of_url(&url, None) == MediaKind::Video,
+            url: url.into_string(),
+        });
+    }
+
+    Ok(Template::render("album/scrape", &context))
+}
+
+/// The images picked from a web page
+#[derive(Debug, Default)]
+pub struct PickForm {
+    title: String,
+    /// Page the images were picked from
+    page: Option<String>,
+    urls: Vec<String>,
+    archive: bool,
+}
+
+// derived forms can not have repeated fields like the checked images
+impl<'f> FromForm<'f> for PickForm {
+    type Error = FormParseError<'f>;
+
+    fn from_form(items: &mut FormItems<'f>, strict: bool) -> Result<Self, Self::Error> {
+        let mut form = PickForm::default();
+
+        for item in items {
+            let value = item
+                .value
+                .url_decode()
+                .map_err(|_| FormParseError::BadValue(item.key, item.value))?;
+
+            match item.key.as_str() {
+                "title" => form.title = value,
+                "page" => form.page = Some(value),
+                "url" => form.urls.push(value),
+                "archive" => {
+                    form.archive = bool::from_form_value(item.value)
+                        .map_err(|_| FormParseError::BadValue(item.key, item.value))?
+                }
+                _ if strict => return Err(FormParseError::Unknown(item.key, item.value)),
+                _ => {}
+            }
+        }
+
+        Ok(form)
+    }
+}
 
-    if links.len() > i32::MAX as usize {
+/// Create an album from the images picked on the page shown by `scrape`.
+///
+/// Images which turn out to be invalid are skipped with a warning.
+#[post("/scrape/create", data = "<sink>")]
+pub fn pick(
+    conn: VDbConn,
+    sink: Result<Form<PickForm>, FormError>,
+    config: State<Config>,
+    storage: State<Storage>,
+) -> Result<Created<Template>, Custom<String>> {
+    let form_result = parse_form(sink)?;
+
+    if form_result.urls.is_empty() {
         return Err(Custom(
             Status::BadRequest,
-            format!("Album has too many images (more than {})", i32::MAX),
+            "Invalid form input: No images were picked".to_string(),
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    let mut links = Vec::new();
+    for (number, url) in form_result.urls.iter().enumerate() {
+        let label = format!("Image {}", number + 1);
+        match validate_url(&config.allowed_domains, url) {
+            Ok(url) => links.push(Link {
+                label,
+                target: LinkTarget::Remote(url),
+                caption: None,
+            }),
+            Err(Custom(_, err)) => warnings.push(format!("{}: {}", label, err)),
+        }
+    }
+
+    if links.is_empty() {
+        return Err(Custom(Status::BadRequest, warnings.join("\n")));
+    }
+
+    let title = if form_result.title.is_empty() {
+        None
+    } else {
+        Some(form_result.title.as_str())
+    };
+    let page = form_result
+        .page
+        .as_deref()
+        .and_then(|page| page.parse().ok());
+
+    let album = NewLinksAlbum {
+        title,
+        archive: form_result.archive,
+        page: page.as_ref(),
+    };
+    if links.len() > MAX_DIRECT_IMAGES {
+        return album.queue(&conn, &links, warnings);
+    }
+    album.create(&conn, links, warnings, &config, &storage)
+}
+
+/// Create an album from a manifest sent as the request body
+#[post("/manifest", format = "json", data = "<data>")]
+pub fn import_manifest(
+    conn: VDbConn,
+    data: Data,
+    config: State<Config>,
+    storage: State<Storage>,
+) -> Result<Created<Template>, Custom<String>> {
+    let limit = config.storage.max_upload_size;
+    let mut json = Vec::new();
+    data.open()
+        .take(limit + 1)
+        .read_to_end(&mut json)
+        .map_err(|err| Custom(Status::BadRequest, format!("Invalid manifest: {}", err)))?;
+    if json.len() as u64 > limit {
+        return Err(Custom(
+            Status::PayloadTooLarge,
+            format!("Manifest is larger than {} bytes", limit),
         ));
     }
 
-    for (index, link) in links.into_iter().enumerate() {
-        let url = parse_url(&link)?;
+    let manifest = Manifest::from_json(&json)
+        .map_err(|err| Custom(Status::BadRequest, format!("{:#}", err)))?;
+    create_from_manifest(&conn, &manifest, &config, &storage)
+}
+
+/// Create an album from an uploaded manifest file
+#[post("/manifest", data = "<sink>", rank = 2)]
+pub fn upload_manifest(
+    conn: VDbConn,
+    sink: Result<MultipartForm, Custom<String>>,
+    config: State<Config>,
+    storage: State<Storage>,
+) -> Result<Created<Template>, Custom<String>> {
+    let mut form = sink?;
+    let data = form.take_file("file")?.read()?;
 
-        if validate_image(&url) {
-            let image = album
-                .add_image(&*conn, url.as_str(), index as i32)
-                .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
-            images.push(image);
+    let manifest = Manifest::from_json(&data)
+        .map_err(|err| Custom(Status::BadRequest, format!("{:#}", err)))?;
+    create_from_manifest(&conn, &manifest, &config, &storage)
+}
+
+/// Get the name of the file stored by this instance which the manifest link
+/// `link` points to.
+///
+/// Stored files are linked relative to the instance or, if it has one, below
+/// its public link. Files which do not exist are not accepted.
+fn stored_file(link: &str, public_url: Option<&Url>, storage: &Storage) -> Option<String> {
+    let name = match file_name(link) {
+        Some(name) => name.to_string(),
+        None => {
+            let files = public_url?.join(&file_url("")).ok()?;
+            let url = Url::parse(link).ok()?;
+            url.as_str().strip_prefix(files.as_str())?.to_string()
         }
+    };
+
+    let path = storage.path(&name).ok()?;
+    if path.is_file() {
+        Some(name)
+    } else {
+        None
     }
+}
 
-    // TODO: show album
-    let mut context = HashMap::new();
-    context.insert("deletion_token", album.deletion_token);
-    context.insert("token", album.token.clone());
-    Ok(Created(
-        format!("/a/{}", album.token),
-        Some(Template::render("album/created", &context)),
-    ))
+/// Create an album with the title, images and captions of `manifest`.
+///
+/// Besides links to allowed domains, links to files stored by this instance
+/// are accepted, so exported albums can be restored. Invalid images are
+/// skipped with a warning.
+fn create_from_manifest(
+    conn: &PgConnection,
+    manifest: &Manifest,
+    config: &Config,
+    storage: &Storage,
+) -> Result<Created<Template>, Custom<String>> {
+    let mut warnings = Vec::new();
+    let mut links = Vec::new();
+    for (number, image) in manifest.images.iter().enumerate() {
+        let label = format!("Image {}", number + 1);
+        let target = match stored_file(&image.url, config.public_url.as_ref(), storage) {
+            Some(name) => Ok(LinkTarget::Stored(name)),
+            // the importing instance can not tell where other relative links
+            // point to
+            None => image.resolve(None).and_then(|url| {
+                validate_url(&config.allowed_domains, url.as_str())
+                    .map(LinkTarget::Remote)
+                    .map_err(|Custom(_, err)| err)
+            }),
+        };
+
+        match target {
+            Ok(target) => links.push(Link {
+                label,
+                target,
+                caption: image
+                    .caption
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|caption| !caption.is_empty())
+                    .map(String::from),
+            }),
+            Err(err) => warnings.push(format!("{}: {}", label, err)),
+        }
+    }
+
+    if links.is_empty() {
+        return Err(Custom(Status::BadRequest, warnings.join("\n")));
+    }
+
+    let title = manifest
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .map(|title| title.chars().take(MAX_TITLE_LENGTH).collect::<String>());
+
+    let album = NewLinksAlbum {
+        title: title.as_deref(),
+        archive: false,
+        page: None,
+    };
+    if links.len() > MAX_DIRECT_IMAGES {
+        return album.queue(conn, &links, warnings);
+    }
+    album.create(conn, links, warnings, config, storage)
 }
 
-/// TODO: check content type
-fn validate_image(_url: &Url) -> bool {
-    true
+#[derive(Debug, FromForm)]
+pub struct ResyncForm {
+    deletion_token: String,
+}
+
+/// Add the images which were added to the source album since the last sync
+#[post("/<token>/resync", data = "<sink>")]
+pub fn resync(
+    conn: VDbConn,
+    token: &RawStr,
+    sink: Result<Form<ResyncForm>, FormError>,
+) -> Result<Redirect, Custom<String>> {
+    let form_result = parse_form(sink)?;
+    let album = get_album(&conn, token)?;
+
+    check_deletion_token(&album, &form_result.deletion_token)?;
+
+    let source = album
+        .source
+        .as_deref()
+        .ok_or_else(|| Custom(Status::BadRequest, "Album was not imported".to_string()))?;
+
+    let running = album
+        .latest_import_job(&conn)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?
+        .map_or(false, |job| !job.status.is_finished());
+
+    if !running {
+        ImportJob::new(&conn, album.id, source)
+            .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+    }
+
+    Ok(Redirect::to(format!("/a/{}/import-status", album.token)))
+}
+
+/// Check that `url` points at an image or video of at most `max_size` bytes
+pub(crate) fn validate_image(url: &Url, max_size: u64) -> Result<Probe, Custom<String>> {
+    let probe = probe(url).map_err(|err| {
+        Custom(
+            Status::BadRequest,
+            format!("Could not check image {}: {}", url, err),
+        )
+    })?;
+
+    if !probe.is_media() {
+        return Err(Custom(
+            Status::BadRequest,
+            format!(
+                "Invalid image: {} is not an image or video (content type {})",
+                url,
+                probe.content_type.as_deref().unwrap_or("unknown")
+            ),
+        ));
+    }
+
+    match probe.size {
+        Some(size) if size > max_size => Err(Custom(
+            Status::BadRequest,
+            format!("Invalid image: {} is larger than {} bytes", url, max_size),
+        )),
+        _ => Ok(probe),
+    }
 }
 
 pub fn validate_url(allowed_domains: &HashSet<String>, url: &str) -> Result<Url, Custom<String>> {
@@ -348,7 +1306,388 @@ pub fn validate_url(allowed_domains: &HashSet<String>, url: &str) -> Result<Url,
         }
     }
 
-    Ok(url)
+    Ok(resolve_gifv(url))
+}
+
+/// An image link which was checked to be allowed, with what was found out
+/// about the image
+enum CheckedImage {
+    Remote(Url, Probe),
+    /// A file stored by this instance with its metadata
+    Stored(String, ImageMetadata),
+}
+
+/// What an image link points to
+#[derive(Debug)]
+enum LinkTarget {
+    /// An allowed link, which may not point at an image
+    Remote(Url),
+    /// The name of a file stored by this instance
+    Stored(String),
+}
+
+/// An image link which is allowed, but may not point at an image
+#[derive(Debug)]
+struct Link {
+    /// Names the link in errors, e.g. `Line 3`
+    label: String,
+    target: LinkTarget,
+    caption: Option<String>,
+}
+
+/// Check every non-empty line of pasted `text` to be an allowed link.
+///
+/// Returns the allowed links in order and an error for every other line.
+/// Fails only if no line is allowed.
+fn check_lines(text: &str, config: &Config) -> Result<(Vec<Link>, Vec<String>), Custom<String>> {
+    let lines: Vec<_> = text
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+
+    if lines.is_empty() {
+        return Err(Custom(
+            Status::BadRequest,
+            "Invalid form input: No image links".to_string(),
+        ));
+    }
+    if lines.len() > MAX_PASTED_LINKS {
+        return Err(Custom(
+            Status::BadRequest,
+            format!(
+                "Invalid form input: More than {} image links",
+                MAX_PASTED_LINKS
+            ),
+        ));
+    }
+
+    let mut links = Vec::new();
+    let mut errors = Vec::new();
+    for (number, line) in lines {
+        let label = format!("Line {}", number + 1);
+        match validate_url(&config.allowed_domains, line) {
+            Ok(url) => links.push(Link {
+                label,
+                target: LinkTarget::Remote(url),
+                caption: None,
+            }),
+            Err(Custom(_, err)) => errors.push(format!("{}: {}", label, err)),
+        }
+    }
+
+    if links.is_empty() {
+        return Err(Custom(Status::BadRequest, errors.join("\n")));
+    }
+
+    Ok((links, errors))
+}
+
+/// Check that the `links` point at valid images.
+///
+/// Returns the images with their captions and adds an error for every
+/// invalid link to `errors`. Fails only if no link is valid.
+fn check_images(
+    links: Vec<Link>,
+    config: &Config,
+    storage: &Storage,
+    errors: &mut Vec<String>,
+) -> Result<Vec<(CheckedImage, Option<String>)>, Custom<String>> {
+    let mut images = Vec::new();
+    for link in links {
+        let image = match link.target {
+            LinkTarget::Remote(url) => validate_image(&url, config.storage.max_download_size)
+                .map(|probe| CheckedImage::Remote(url, probe))
+                .map_err(|Custom(_, err)| err),
+            LinkTarget::Stored(name) => storage
+                .reuse(&name)
+                .map(|data| CheckedImage::Stored(name, metadata::from_data(&data)))
+                .map_err(|err| format!("{:#}", err)),
+        };
+
+        match image {
+            Ok(image) => images.push((image, link.caption)),
+            Err(err) => errors.push(format!("{}: {}", link.label, err)),
+        }
+    }
+
+    if images.is_empty() {
+        return Err(Custom(Status::BadRequest, errors.join("\n")));
+    }
+
+    Ok(images)
+}
+
+/// Insert checked images with their captions in order, the first one at
+/// `index`.
+///
+/// Returns the added images with their perceptual hashes.
+fn add_images(
+    conn: &PgConnection,
+    album: &Album,
+    images: Vec<(CheckedImage, Option<String>)>,
+    index: i32,
+    storage: &Storage,
+) -> Result<Vec<(Image, Option<i64>)>> {
+    let mut added = Vec::new();
+    for (offset, (checked, caption)) in images.into_iter().enumerate() {
+        let index = index + offset as i32;
+        album.increase_index(conn, index)?;
+
+        let (image, metadata) = match checked {
+            CheckedImage::Remote(url, probe) => {
+                let image = album.add_image(conn, storage, url.as_str(), index)?;
+                let metadata = remote_metadata(storage, &image, &url, &probe);
+                (image, metadata)
+            }
+            CheckedImage::Stored(name, metadata) => (album.add_file(conn, &name, index)?, metadata),
+        };
+        save_metadata(conn, &image, &metadata);
+        if caption.is_some() {
+            image.set_caption(conn, caption.as_deref())?;
+        }
+
+        added.push((image, metadata.phash));
+    }
+
+    Ok(added)
+}
+
+/// Queue a job adding the images at `links` to `album` from `index` on
+fn queue_links(
+    conn: &PgConnection,
+    album: &Album,
+    page: Option<&Url>,
+    links: &[Link],
+    index: i32,
+) -> Result<ImportJob> {
+    let urls: Vec<_> = links
+        .iter()
+        .map(|link| match &link.target {
+            LinkTarget::Remote(url) => url.to_string(),
+            LinkTarget::Stored(name) => file_url(name),
+        })
+        .collect();
+    let captions: Vec<_> = links
+        .iter()
+        .map(|link| link.caption.clone().unwrap_or_default())
+        .collect();
+
+    NewLinksJob {
+        album_id: album.id,
+        url: page.map(Url::as_str),
+        links: &urls,
+        captions: &captions,
+        insert_at: index,
+    }
+    .insert(conn)
+}
+
+/// A new album created from a list of image links
+#[derive(Debug, Clone, Copy)]
+struct NewLinksAlbum<'a> {
+    title: Option<&'a str>,
+    archive: bool,
+    /// Page the links were found on
+    page: Option<&'a Url>,
+}
+
+impl NewLinksAlbum<'_> {
+    /// Create the album with the images at `links` right away. It is only
+    /// created if all valid images could be added.
+    fn create(
+        self,
+        conn: &PgConnection,
+        links: Vec<Link>,
+        mut warnings: Vec<String>,
+        config: &Config,
+        storage: &Storage,
+    ) -> Result<Created<Template>, Custom<String>> {
+        let images = check_images(links, config, storage, &mut warnings)?;
+
+        let (album, hashes) = conn
+            .transaction::<_, anyhow::Error, _>(|| {
+                let album = Album::new(conn, self.title, self.archive)?;
+                let hashes = add_images(conn, &album, images, 0, storage)?
+                    .into_iter()
+                    .filter_map(|(image, phash)| Some((image.index, phash?)))
+                    .collect::<Vec<_>>();
+                Ok((album, hashes))
+            })
+            .map_err(|err| Custom(Status::InternalServerError, format!("{:#}", err)))?;
+        warnings.extend(import_warnings(conn, &album, &hashes));
+
+        Ok(created(album, warnings, false))
+    }
+
+    /// Create the album and let the import worker add the images at `links`
+    fn queue(
+        self,
+        conn: &PgConnection,
+        links: &[Link],
+        warnings: Vec<String>,
+    ) -> Result<Created<Template>, Custom<String>> {
+        let album = conn
+            .transaction(|| {
+                let album = Album::new(conn, self.title, self.archive)?;
+                queue_links(conn, &album, self.page, links, 0)?;
+                Ok(album)
+            })
+            .map_err(|err: anyhow::Error| Custom(Status::InternalServerError, err.to_string()))?;
+
+        Ok(created(album, warnings, true))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedContext {
+    pub token: String,
+    pub deletion_token: String,
+    pub warnings: Vec<String>,
+    /// Whether the images are still being added by the import worker
+    pub queued: bool,
+}
+
+fn created(album: Album, warnings: Vec<String>, queued: bool) -> Created<Template> {
+    // TODO: show album
+    let context = CreatedContext {
+        token: album.token.clone(),
+        deletion_token: album.deletion_token,
+        warnings,
+        queued,
+    };
+    Created(
+        format!("/a/{}", album.token),
+        Some(Template::render("album/created", &context)),
+    )
+}
+
+/// Warn if the album already contains an image which looks like `image`
+fn duplicate_warning(
+    conn: &PgConnection,
+    album: &Album,
+    image: &Image,
+    phash: Option<i64>,
+) -> Option<String> {
+    let phash = phash?;
+    let hashes = match album.image_hashes(conn) {
+        Ok(hashes) => hashes,
+        Err(err) => {
+            warn!("Could not look for duplicates: {}", err);
+            return None;
+        }
+    };
+
+    let duplicates: Vec<_> = hashes
+        .into_iter()
+        .filter(|&(index, other)| index != image.index && is_similar(phash, other))
+        .map(|(index, _)| format!("#{}", index))
+        .collect();
+
+    if duplicates.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "The new image looks like image {} of this album",
+            duplicates.join(", ")
+        ))
+    }
+}
+
+/// Warn about images which look alike within an imported album and images
+/// which already exist in other albums
+pub(crate) fn import_warnings(
+    conn: &PgConnection,
+    album: &Album,
+    hashes: &[(i32, i64)],
+) -> Vec<String> {
+    let mut warnings: Vec<_> = clusters(hashes)
+        .into_iter()
+        .map(|cluster| {
+            let indexes: Vec<_> = cluster.iter().map(|index| format!("#{}", index)).collect();
+            format!("Images {} look alike", indexes.join(", "))
+        })
+        .collect();
+
+    let hashes: Vec<_> = hashes.iter().map(|&(_, phash)| phash).collect();
+    let existing = match Image::count_similar(conn, album.id, &hashes) {
+        Ok(existing) => existing,
+        Err(err) => {
+            warn!("Could not look for duplicates: {:#}", err);
+            return warnings;
+        }
+    };
+    if existing > 0 {
+        warnings.push(format!(
+            "{} of the imported images already exist in other albums",
+            existing
+        ));
+    }
+
+    warnings
+}
+
+/// Store an uploaded file and return its name and metadata.
+///
+/// The file is prepared like every other file v stores, see
+/// [`Storage::prepare`].
+fn store_file(
+    storage: &Storage,
+    filename: &str,
+    data: Vec<u8>,
+) -> Result<(String, ImageMetadata), Custom<String>> {
+    if detect_format(&data).is_none() {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("Unsupported file format: {}", filename),
+        ));
+    }
+
+    let data = storage.prepare(data).map_err(|err| {
+        Custom(
+            Status::BadRequest,
+            format!("Could not process upload: {:#}", err),
+        )
+    })?;
+
+    let name = storage
+        .store(&data)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+
+    Ok((name, metadata::from_data(&data)))
+}
+
+/// Save the metadata of a newly added image.
+///
+/// Missing metadata only affects how the image is displayed, so failures are
+/// logged instead of failing the request.
+pub(crate) fn save_metadata(conn: &PgConnection, image: &Image, metadata: &ImageMetadata) {
+    if let Err(err) = image.set_metadata(conn, metadata) {
+        warn!("Could not save metadata of image {}: {}", image.token, err);
+    }
+}
+
+/// Get the metadata of a newly added remote image.
+///
+/// Mirrored images are read from their local copy, which also allows
+/// computing their placeholder.
+pub(crate) fn remote_metadata(
+    storage: &Storage,
+    image: &Image,
+    url: &Url,
+    probe: &Probe,
+) -> ImageMetadata {
+    let data = image.file.as_ref().map(|file| storage.read(file));
+
+    match data {
+        Some(Ok(data)) => metadata::from_data(&data),
+        Some(Err(err)) => {
+            warn!("Could not read mirrored image {}: {}", image.token, err);
+            metadata::from_remote(url, probe)
+        }
+        None => metadata::from_remote(url, probe),
+    }
 }
 
 fn parse_form<'a, F>(sink: Result<F, FormError>) -> Result<F, Custom<String>>
@@ -374,16 +1713,24 @@ fn get_album(conn: &PgConnection, token: &str) -> Result<Album, Custom<String>>
         .ok_or(Custom(Status::NotFound, "Could not find album".into()))
 }
 
-fn get_image_urls(conn: &PgConnection, album: &Album) -> Result<Vec<String>, Custom<String>> {
-    album
-        .get_image_urls(conn)
-        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))
-}
+/// Get the images on the current page and fill in whether there is a next page
+fn get_images_page(
+    conn: &PgConnection,
+    album: &Album,
+    pagination: &mut Pagination,
+) -> Result<Vec<Image>, Custom<String>> {
+    // fetch one more image than needed to know if there is a next page
+    let mut images = album
+        .get_images_from(conn, pagination.start(), pagination.limit as i64 + 1)
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
 
-fn get_images(conn: &PgConnection, album: &Album) -> Result<Vec<Image>, Custom<String>> {
-    album
-        .get_images(conn)
-        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))
+    let end = pagination.end();
+    if let Some(pos) = images.iter().position(|image| image.index >= end) {
+        images.truncate(pos);
+        pagination.next = Some(pagination.page + 1);
+    }
+
+    Ok(images)
 }
 
 fn check_deletion_token(album: &Album, deletion_token: &str) -> Result<(), Custom<String>> {
@@ -408,34 +1755,139 @@ fn check_deletion_token_cookie(album: &Album, cookies: &mut Cookies) -> Result<(
     )
 }
 
-fn parse_url(url: &str) -> Result<Url, Custom<String>> {
-    url.parse()
-        .map_err(|err| Custom(Status::BadRequest, format!("Invalid form input: {}", err)))
+/// Make an album title safe to use as a file name
+fn archive_name(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.'))
+        .collect::<String>()
+        .trim_matches(|c| c == ' ' || c == '.')
+        .to_string()
 }
 
-fn insert_image(
-    conn: &PgConnection,
-    album: &Album,
-    form: EditAlbumForm,
-) -> Result<(), Custom<String>> {
+/// Format a number of bytes for humans, e.g. `1.5 MiB`
+fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Describe how long ago `time` was, e.g. `3 hours ago`
+fn format_age(time: SystemTime) -> String {
+    const UNITS: [(u64, &str); 4] = [
+        (24 * 60 * 60, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+
+    match UNITS.iter().find(|&&(len, _)| secs >= len) {
+        Some(&(len, unit)) => {
+            let count = secs / len;
+            format!(
+                "{} {}{} ago",
+                count,
+                unit,
+                if count == 1 { "" } else { "s" }
+            )
+        }
+        None => "just now".to_string(),
+    }
+}
+
+/// Convert an image index sent in a form to the index stored in the database
+fn image_index(index: u32) -> Result<i32, Custom<String>> {
+    i32::try_from(index).map_err(|_| {
+        Custom(
+            Status::BadRequest,
+            format!("Invalid form input: index {} is too large", index),
+        )
+    })
+}
+
+/// Move the images at and after `index` back by one
+fn make_room(conn: &PgConnection, album: &Album, index: i32) -> Result<(), Custom<String>> {
     let image_count = album
         .image_count(conn)
         .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
-    let url = parse_url(&form.url)?;
 
-    if image_count > form.index as usize {
+    if image_count > index as usize {
         album
-            .increase_index(conn, form.index as i32)
+            .increase_index(conn, index)
             .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
     }
 
-    album
-        .add_image(&*conn, url.as_str(), form.index as i32)
-        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
-
     Ok(())
 }
 
+fn insert_image(
+    conn: &PgConnection,
+    album: &Album,
+    index: i32,
+    urls: &str,
+    config: &Config,
+    storage: &Storage,
+) -> Result<Vec<String>, Custom<String>> {
+    let (links, mut warnings) = check_lines(urls, config)?;
+
+    if links.len() > MAX_DIRECT_IMAGES {
+        queue_links(conn, album, None, &links, index)
+            .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?;
+        warnings.push(format!(
+            "The {} images are added in the background, see /a/{}/import-status",
+            links.len(),
+            album.token
+        ));
+        return Ok(warnings);
+    }
+
+    let images = check_images(links, config, storage, &mut warnings)?;
+    let added = conn
+        .transaction(|| add_images(conn, album, images, index, storage))
+        .map_err(|err| Custom(Status::InternalServerError, format!("{:#}", err)))?;
+    for (image, phash) in added {
+        warnings.extend(duplicate_warning(conn, album, &image, phash));
+    }
+
+    Ok(warnings)
+}
+
+fn delete_image(conn: &PgConnection, album: &Album, index: i32) -> Result<(), Custom<String>> {
+    let deleted = conn
+        .transaction(|| {
+            let image = match album.get_image_at(conn, index)? {
+                Some(image) => image,
+                None => return Ok(false),
+            };
+
+            // an unused file is removed by the next garbage collection
+            image.delete(conn)?;
+            album.decrease_index(conn, index + 1)?;
+            Ok(true)
+        })
+        .map_err(|err: anyhow::Error| Custom(Status::InternalServerError, err.to_string()))?;
+
+    deleted
+        .then_some(())
+        .ok_or_else(|| Custom(Status::NotFound, "Could not find image".to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -449,6 +1901,8 @@ mod tests {
                     token: String::from("sladjhf"),
                     deletion_token: String::from("2hasdl3akls"),
                     title: None,
+                    archive: false,
+                    source: None,
                 },
                 "2hasdl3akls"
             )
@@ -463,6 +1917,8 @@ mod tests {
                     token: String::from("sladjhf"),
                     deletion_token: String::from("2hasdl3akls"),
                     title: None,
+                    archive: false,
+                    source: None,
                 },
                 "k23hfsoduzf2"
             )
@@ -471,6 +1927,59 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pagination() {
+        let pagination = Pagination::new(Some(3), Some(20)).unwrap();
+        assert_eq!(pagination.start(), 40);
+        assert_eq!(pagination.end(), 60);
+        assert_eq!(pagination.prev, Some(2));
+
+        assert!(Pagination::new(Some(0), None).is_err());
+        assert!(Pagination::new(None, Some(0)).is_err());
+        assert!(Pagination::new(None, Some(MAX_PAGE_LIMIT + 1)).is_err());
+    }
+
+    #[test]
+    fn test_pagination_containing() {
+        assert_eq!(Pagination::containing(0, None).unwrap().page, 1);
+        assert_eq!(Pagination::containing(49, None).unwrap().page, 1);
+        assert_eq!(Pagination::containing(50, None).unwrap().page, 2);
+
+        let pagination = Pagination::containing(70_000, Some(20)).unwrap();
+        assert_eq!(pagination.page, 3501);
+        assert_eq!(pagination.limit, 20);
+
+        assert!(Pagination::containing(0, Some(0)).is_err());
+    }
+
+    #[test]
+    fn test_format_age() {
+        use std::time::Duration;
+
+        let now = SystemTime::now();
+        assert_eq!(format_age(now + Duration::from_secs(5)), "just now");
+        assert_eq!(format_age(now - Duration::from_secs(90)), "1 minute ago");
+        assert_eq!(
+            format_age(now - Duration::from_secs(3 * 3600)),
+            "3 hours ago"
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(20 * 1024 * 1024), "20.0 MiB");
+    }
+
+    #[test]
+    fn test_archive_name() {
+        assert_eq!(archive_name("My Album 2020"), "My Album 2020");
+        assert_eq!(archive_name("../\"evil\"/.."), "evil");
+        assert_eq!(archive_name("/"), "");
+    }
+
     #[test]
     fn test_check_deletion_token_trim() {
         assert!(
@@ -480,6 +1989,8 @@ mod tests {
                     token: String::from("sladjhf"),
                     deletion_token: String::from("2hasdl3akls"),
                     title: None,
+                    archive: false,
+                    source: None,
                 },
                 "  2hasdl3akls  "
             )
@@ -0,0 +1,8 @@
+use crate::storage::Storage;
+use rocket::{http::RawStr, response::NamedFile, State};
+
+#[get("/f/<name>")]
+pub fn get(name: &RawStr, storage: State<Storage>) -> Option<NamedFile> {
+    let path = storage.path(name.as_str()).ok()?;
+    NamedFile::open(path).ok()
+}
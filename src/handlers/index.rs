@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 
+use crate::import::{Importers, Source};
 use log::trace;
-use rocket::{http::Status, response::status::Custom};
+use rocket::{http::Status, response::status::Custom, State};
 use rocket_contrib::templates::Template;
+use serde::Serialize;
 
 #[get("/")]
 pub fn get() -> Template {
@@ -22,9 +24,19 @@ pub fn new() -> Template {
     Template::render("new", ())
 }
 
+#[derive(Debug, Serialize)]
+pub struct ImportContext {
+    pub sources: Vec<Source>,
+}
+
 #[get("/import")]
-pub fn import() -> Template {
-    Template::render("import", ())
+pub fn import(importers: State<Importers>) -> Template {
+    Template::render(
+        "import",
+        ImportContext {
+            sources: importers.sources(),
+        },
+    )
 }
 
 #[get("/FeelsDankMan")]
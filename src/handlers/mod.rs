@@ -4,8 +4,11 @@ use rocket::Request;
 use rocket_contrib::templates::Template;
 
 pub mod album;
+pub mod files;
 pub mod index;
 pub mod static_files;
+pub mod thumbs;
+pub mod transforms;
 
 #[catch(404)]
 pub fn not_found(req: &Request) -> Template {
@@ -0,0 +1,68 @@
+use crate::{
+    config::Config, fetch::download, handlers::album::validate_url, media::MediaKind,
+    models::Image, storage::detect_format, storage::Storage, thumbnails::Thumbnails, VDbConn,
+};
+use anyhow::anyhow;
+use diesel::{OptionalExtension, RunQueryDsl};
+use log::warn;
+use rocket::{
+    http::{ContentType, RawStr, Status},
+    response::{status::Custom, Content, Redirect},
+    State,
+};
+
+#[derive(Debug, Responder)]
+pub enum Thumbnail {
+    Image(Content<Vec<u8>>),
+    /// Used if no thumbnail can be generated, e.g. for videos
+    Original(Redirect),
+}
+
+#[get("/thumb/<token>/<width>")]
+pub fn get(
+    conn: VDbConn,
+    token: &RawStr,
+    width: u32,
+    config: State<Config>,
+    storage: State<Storage>,
+    thumbnails: State<Thumbnails>,
+) -> Result<Thumbnail, Custom<String>> {
+    if !thumbnails.is_allowed_width(width) {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("Invalid thumbnail width {}", width),
+        ));
+    }
+
+    let image = Image::by_token(token)
+        .first::<Image>(&*conn)
+        .optional()
+        .map_err(|err| Custom(Status::InternalServerError, err.to_string()))?
+        .ok_or_else(|| Custom(Status::NotFound, "Could not find image".to_string()))?;
+
+    if image.kind == MediaKind::Video {
+        return Ok(Thumbnail::Original(Redirect::to(image.url)));
+    }
+
+    let source = || match &image.file {
+        Some(file) => storage.read(file),
+        None => {
+            let url = validate_url(&config.allowed_domains, &image.url)
+                .map_err(|Custom(_, err)| anyhow!(err))?;
+            download(&url, config.storage.max_download_size)
+        }
+    };
+
+    match thumbnails.get(&image.token, width, source) {
+        Ok(data) => {
+            let content_type = detect_format(&data)
+                .and_then(ContentType::from_extension)
+                .unwrap_or(ContentType::Binary);
+            Ok(Thumbnail::Image(Content(content_type, data)))
+        }
+        Err(err) => {
+            warn!("Could not get thumbnail of image {}: {}", image.token, err);
+            Ok(Thumbnail::Original(Redirect::to(image.url)))
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{
+    media::MediaKind,
+    storage::Storage,
+    transforms::{Fit, Format, Transform, Transforms},
+};
+use log::warn;
+use rocket::{
+    http::{ContentType, RawStr, Status},
+    request::LenientForm,
+    response::{status::Custom, Content},
+    State,
+};
+
+/// Query parameters of a transform request
+#[derive(Debug, FromForm)]
+pub struct TransformQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<String>,
+    format: Option<String>,
+    q: Option<u8>,
+}
+
+/// Serve the stored image `name` resized, cropped and re-encoded.
+///
+/// Only transforms matching one of the configured presets are allowed.
+#[get("/transform/<name>?<query..>")]
+pub fn get(
+    name: &RawStr,
+    query: LenientForm<TransformQuery>,
+    storage: State<Storage>,
+    transforms: State<Transforms>,
+) -> Result<Content<Vec<u8>>, Custom<String>> {
+    let bad_request = |err: String| Custom(Status::BadRequest, err);
+
+    let transform = Transform {
+        width: query.w,
+        height: query.h,
+        fit: query
+            .fit
+            .as_ref()
+            .map_or(Ok(Fit::default()), |fit| fit.parse())
+            .map_err(bad_request)?,
+        format: query
+            .format
+            .as_ref()
+            .ok_or_else(|| "Missing format".to_string())
+            .and_then(|format| format.parse::<Format>())
+            .map_err(bad_request)?,
+        quality: query.q,
+    };
+
+    transform.validate().map_err(bad_request)?;
+    if !transforms.is_allowed(&transform) {
+        return Err(bad_request("Transform is not allowed".to_string()));
+    }
+
+    let path = storage
+        .path(name)
+        .ok()
+        .filter(|path| path.exists())
+        .ok_or_else(|| Custom(Status::NotFound, "Could not find file".to_string()))?;
+
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    if MediaKind::detect(extension, None) == MediaKind::Video {
+        return Err(bad_request("Videos can not be transformed".to_string()));
+    }
+
+    let data = transforms
+        .get(name, &transform, || storage.read(name))
+        .map_err(|err| {
+            warn!("Could not transform file {}: {:#}", name, err);
+            Custom(
+                Status::InternalServerError,
+                "Could not transform image".to_string(),
+            )
+        })?;
+
+    // Rocket does not know AVIF
+    let content_type = match transform.format {
+        Format::Avif => ContentType::new("image", "avif"),
+        format => ContentType::from_extension(format.extension()).unwrap_or(ContentType::Binary),
+    };
+
+    Ok(Content(content_type, data))
+}
@@ -1,39 +1,431 @@
-use anyhow::{ensure, Result};
-use reqwest::blocking::Client;
-use serde::Deserialize;
+use crate::{
+    config::ImgurConfig,
+    fetch::client,
+    import::{Importer, RemoteAlbum},
+};
+use anyhow::{anyhow, bail, Result};
+use log::debug;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime},
+};
+use url::Url;
+
+/// Number of times a request is retried after a transient failure
+const MAX_RETRIES: u32 = 3;
+/// Delay before the first retry, doubled for every further retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest rate limit reset the server may ask us to wait for
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Image hashes are 5 or 7 characters long, which tells them apart from
+/// pages like `imgur.com/upload`
+const IMAGE_HASH_LENGTHS: [usize; 2] = [5, 7];
 
 #[derive(Debug, Deserialize)]
 struct Image {
     pub link: String,
+    /// Video version of animated images
+    pub mp4: Option<String>,
+}
+
+impl Image {
+    fn into_link(self) -> String {
+        self.mp4.unwrap_or(self.link)
+    }
+}
+
+/// A gallery post, which is either an album or a single image
+#[derive(Debug, Deserialize)]
+struct GalleryItem {
+    pub is_album: bool,
+    #[serde(flatten)]
+    pub image: Option<Image>,
 }
 
+/// The envelope of every API response
 #[derive(Debug, Deserialize)]
-struct AlbumImages {
-    pub data: Vec<Image>,
-    pub status: u8,
-}
-
-pub fn get_album_images(client_id: &str, album_hash: &str) -> Result<Vec<String>> {
-    let client = Client::new();
-    let resp = client
-        .get(&format!(
-            "https://api.imgur.com/3/album/{}/images",
-            album_hash
-        ))
-        .header("Authorization", format!("Client-ID {}", client_id))
-        .send()?;
-
-    ensure!(
-        resp.status().is_success(),
-        format!("Imgur said: {}", resp.status())
-    );
-
-    let json = resp.json::<AlbumImages>()?;
-
-    ensure!(
-        json.status == 200,
-        format!("Imgur API status is {}", json.status)
-    );
-
-    Ok(json.data.iter().map(|image| image.link.clone()).collect())
+struct Response<T> {
+    pub data: T,
+    pub status: u16,
+}
+
+/// The things an Imgur link can point to, identified by their hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImgurLink {
+    /// `imgur.com/a/<hash>`
+    Album(String),
+    /// `imgur.com/gallery/<hash>` and `imgur.com/t/<tag>/<hash>`
+    Gallery(String),
+    /// `imgur.com/<hash>` and `i.imgur.com/<hash>.<ext>`
+    Image(String),
+}
+
+impl ImgurLink {
+    /// Parse any Imgur link to an album, gallery post or image.
+    ///
+    /// Titles in front of the hash (`/a/some-title-<hash>`), file extensions,
+    /// the `www.`, `m.` and `i.` subdomains and trailing slashes are accepted.
+    pub fn parse(url: &Url) -> Result<Self, String> {
+        if !is_imgur(url) {
+            return Err(format!("Not an Imgur link: {}", url));
+        }
+
+        let segments: Vec<_> = url
+            .path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+
+        let link = match segments.as_slice() {
+            ["a", slug] => slug_hash(slug).map(ImgurLink::Album),
+            ["gallery", slug] | ["t", _, slug] => slug_hash(slug).map(ImgurLink::Gallery),
+            [name] => {
+                // i.imgur.com links have an extension, image pages may too
+                let hash = name.split('.').next().unwrap_or_default();
+                (is_hash(hash) && IMAGE_HASH_LENGTHS.contains(&hash.len()))
+                    .then(|| ImgurLink::Image(hash.to_string()))
+            }
+            _ => None,
+        };
+
+        link.ok_or_else(|| {
+            format!(
+                "Not a link to an Imgur album, gallery post or image: {}",
+                url
+            )
+        })
+    }
+}
+
+fn is_imgur(url: &Url) -> bool {
+    match url.domain().map(str::to_lowercase).as_deref() {
+        Some("imgur.com") | Some("www.imgur.com") | Some("m.imgur.com") | Some("i.imgur.com") => {
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Get the hash at the end of `slug`, which may be prefixed with a title
+fn slug_hash(slug: &str) -> Option<String> {
+    let hash = slug.rsplit('-').next()?;
+    is_hash(hash).then(|| hash.to_string())
+}
+
+fn is_hash(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Which requests are retried after a delay
+#[derive(Debug)]
+enum Failure {
+    /// Timeouts, server errors and short rate limits, with the delay
+    /// requested by the server
+    Transient(anyhow::Error, Option<Duration>),
+    Permanent(anyhow::Error),
+}
+
+/// The remaining requests the API allows, as sent with every response
+#[derive(Debug, Default, Clone, Copy)]
+struct RateLimit {
+    remaining: Option<u64>,
+    reset: Option<SystemTime>,
+}
+
+impl RateLimit {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let number = |name: &str| -> Option<u64> {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+        };
+
+        // the client and the user (our ip address) are limited separately
+        let remaining = match (
+            number("X-RateLimit-ClientRemaining"),
+            number("X-RateLimit-UserRemaining"),
+        ) {
+            (Some(client), Some(user)) => Some(client.min(user)),
+            (client, user) => client.or(user),
+        };
+        let reset = number("X-RateLimit-UserReset")
+            .map(|reset| SystemTime::UNIX_EPOCH + Duration::from_secs(reset));
+
+        RateLimit { remaining, reset }
+    }
+
+    /// Get the time until requests are allowed again, if none are left
+    fn exhausted_for(&self, now: SystemTime) -> Option<Duration> {
+        match (self.remaining, self.reset) {
+            (Some(0), Some(reset)) => reset.duration_since(now).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Imports albums, gallery posts and single images from Imgur
+#[derive(Debug)]
+pub struct Imgur {
+    client_id: String,
+    api_url: String,
+    rate_limit: Mutex<RateLimit>,
+}
+
+impl Imgur {
+    pub fn new(config: &ImgurConfig) -> Self {
+        Imgur {
+            client_id: config.client_id.clone(),
+            api_url: config.api_url.trim_end_matches('/').to_string(),
+            rate_limit: Mutex::new(RateLimit::default()),
+        }
+    }
+
+    /// Request `path` from the Imgur API, retrying transient failures with
+    /// exponential backoff
+    fn request<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}/{}", self.api_url, path);
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = 0;
+
+        loop {
+            let exhausted = self
+                .rate_limit
+                .lock()
+                .map_err(|_| anyhow!("Imgur rate limit is poisoned"))?
+                .exhausted_for(SystemTime::now());
+            if let Some(wait) = exhausted {
+                bail!(
+                    "Imgur rate limit reached, try again in {} minutes",
+                    wait.as_secs() / 60 + 1
+                );
+            }
+
+            match self.send(&url) {
+                Ok(data) => return Ok(data),
+                Err(Failure::Transient(err, delay)) if retries < MAX_RETRIES => {
+                    let delay = delay.unwrap_or(backoff);
+                    debug!("{:#}, retrying in {:?}", err, delay);
+                    thread::sleep(delay);
+                    backoff *= 2;
+                    retries += 1;
+                }
+                Err(Failure::Transient(err, _)) | Err(Failure::Permanent(err)) => return Err(err),
+            }
+        }
+    }
+
+    fn send<T: DeserializeOwned>(&self, url: &str) -> Result<T, Failure> {
+        let resp = client()
+            .get(url)
+            .header("Authorization", format!("Client-ID {}", self.client_id))
+            .send()
+            .map_err(|err| {
+                let transient = err.is_timeout() || err.is_request();
+                let err = anyhow!(err).context("Could not reach Imgur");
+                if transient {
+                    Failure::Transient(err, None)
+                } else {
+                    Failure::Permanent(err)
+                }
+            })?;
+
+        let rate_limit = RateLimit::from_headers(resp.headers());
+        if rate_limit.remaining.is_some() {
+            if let Ok(mut current) = self.rate_limit.lock() {
+                *current = rate_limit;
+            }
+        }
+
+        let status = resp.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs);
+            let err = anyhow!("Imgur rate limit reached");
+
+            // do not keep a request waiting for long limits to reset
+            return Err(match retry_after {
+                Some(delay) if delay > MAX_RETRY_AFTER => Failure::Permanent(err),
+                delay => Failure::Transient(err, delay),
+            });
+        }
+        if status.is_server_error() {
+            return Err(Failure::Transient(anyhow!("Imgur said: {}", status), None));
+        }
+        if !status.is_success() {
+            return Err(Failure::Permanent(anyhow!("Imgur said: {}", status)));
+        }
+
+        let json = resp
+            .json::<Response<T>>()
+            .map_err(|err| Failure::Permanent(anyhow!(err).context("Invalid Imgur response")))?;
+
+        if json.status != 200 {
+            return Err(Failure::Permanent(anyhow!(
+                "Imgur API status is {}",
+                json.status
+            )));
+        }
+
+        Ok(json.data)
+    }
+
+    fn album_images(&self, album_hash: &str) -> Result<Vec<String>> {
+        let images: Vec<Image> = self.request(&format!("album/{}/images", album_hash))?;
+
+        Ok(images.into_iter().map(Image::into_link).collect())
+    }
+
+    fn gallery_images(&self, gallery_hash: &str) -> Result<Vec<String>> {
+        let item: GalleryItem = self.request(&format!("gallery/{}", gallery_hash))?;
+
+        if item.is_album {
+            // gallery albums share their hash with the album
+            return self.album_images(gallery_hash);
+        }
+
+        let image = item
+            .image
+            .ok_or_else(|| anyhow!("Gallery post {} has no image", gallery_hash))?;
+
+        Ok(vec![image.into_link()])
+    }
+
+    fn image(&self, image_hash: &str) -> Result<Vec<String>> {
+        let image: Image = self.request(&format!("image/{}", image_hash))?;
+
+        Ok(vec![image.into_link()])
+    }
+}
+
+impl Importer for Imgur {
+    fn name(&self) -> &'static str {
+        "Imgur"
+    }
+
+    fn example(&self) -> &'static str {
+        "https://imgur.com/a/JrheYnV"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_imgur(url)
+    }
+
+    fn validate(&self, url: &Url) -> Result<(), String> {
+        ImgurLink::parse(url).map(|_| ())
+    }
+
+    fn album(&self, url: &Url) -> Result<RemoteAlbum> {
+        let image_urls = match ImgurLink::parse(url).map_err(|err| anyhow!(err))? {
+            ImgurLink::Album(hash) => self.album_images(&hash)?,
+            ImgurLink::Gallery(hash) => self.gallery_images(&hash)?,
+            ImgurLink::Image(hash) => self.image(&hash)?,
+        };
+
+        Ok(RemoteAlbum {
+            title: None,
+            image_urls,
+            ..RemoteAlbum::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    const ALBUM: &str = r#"{"data":[{"link":"https://i.imgur.com/a.png"},{"link":"https://i.imgur.com/b.gif","mp4":"https://i.imgur.com/b.mp4"}],"status":200}"#;
+
+    fn imgur(responses: Vec<String>) -> Imgur {
+        Imgur::new(&ImgurConfig {
+            client_id: "test".to_string(),
+            api_url: mock::serve(responses).to_string(),
+        })
+    }
+
+    fn parse(url: &str) -> Result<ImgurLink, String> {
+        ImgurLink::parse(&url.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        let album = || Ok(ImgurLink::Album("JrheYnV".to_string()));
+        let gallery = || Ok(ImgurLink::Gallery("JrheYnV".to_string()));
+        let image = || Ok(ImgurLink::Image("VoyouQH".to_string()));
+
+        assert_eq!(parse("https://imgur.com/a/JrheYnV"), album());
+        assert_eq!(parse("https://m.imgur.com/a/JrheYnV/"), album());
+        assert_eq!(parse("https://imgur.com/a/my-cats-JrheYnV"), album());
+        assert_eq!(parse("https://www.imgur.com/gallery/JrheYnV"), gallery());
+        assert_eq!(
+            parse("https://imgur.com/gallery/my-cats-JrheYnV"),
+            gallery()
+        );
+        assert_eq!(parse("https://imgur.com/t/cats/JrheYnV"), gallery());
+        assert_eq!(parse("https://imgur.com/VoyouQH"), image());
+        assert_eq!(parse("https://i.imgur.com/VoyouQH.png"), image());
+        assert_eq!(parse("https://I.IMGUR.COM/VoyouQH.mp4"), image());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse("https://imgur.com").is_err());
+        assert!(parse("https://imgur.com/a/").is_err());
+        assert!(parse("https://imgur.com/upload").is_err());
+        assert!(parse("https://imgur.com/a/b/c/d").is_err());
+        assert!(parse("https://imgur.com/a/ü").is_err());
+        assert!(parse("https://example.com/a/JrheYnV").is_err());
+        assert!(parse("https://notimgur.com/a/JrheYnV").is_err());
+    }
+
+    #[test]
+    fn test_retry() {
+        let imgur = imgur(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_string(),
+            mock::json(ALBUM, &[]),
+        ]);
+
+        assert_eq!(
+            imgur.album_images("abc").unwrap(),
+            vec!["https://i.imgur.com/a.png", "https://i.imgur.com/b.mp4"]
+        );
+    }
+
+    #[test]
+    fn test_no_retry() {
+        let imgur = imgur(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string()
+        ]);
+
+        let err = imgur.album_images("abc").unwrap_err();
+        assert!(err.to_string().contains("404"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let imgur = imgur(vec![mock::json(
+            ALBUM,
+            &[
+                ("X-RateLimit-ClientRemaining", "100"),
+                ("X-RateLimit-UserRemaining", "0"),
+                ("X-RateLimit-UserReset", "4102444800"),
+            ],
+        )]);
+
+        assert!(imgur.album_images("abc").is_ok());
+
+        let err = imgur.album_images("abc").unwrap_err();
+        assert!(err.to_string().contains("rate limit"));
+    }
 }
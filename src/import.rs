@@ -0,0 +1,147 @@
+use crate::{config::Config, imgur::Imgur, instance::Instances, reddit::Reddit};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use url::Url;
+
+/// A site albums can be imported from
+pub trait Importer: Send + Sync {
+    /// Name of the site shown to users, e.g. `Imgur`
+    fn name(&self) -> &'static str;
+
+    /// Example of a link this importer accepts
+    fn example(&self) -> &'static str;
+
+    /// Returns true if this importer can import the album at `url`
+    fn matches(&self, url: &Url) -> bool;
+
+    /// Check that `url` has the shape of an importable link before the
+    /// import is queued, without contacting the site
+    fn validate(&self, _url: &Url) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Get the title and images of the album at `url`
+    fn album(&self, url: &Url) -> Result<RemoteAlbum>;
+}
+
+/// An album on another site
+#[derive(Debug, Default)]
+pub struct RemoteAlbum {
+    /// Title of the album, if the site has one
+    pub title: Option<String>,
+    /// Links of all images in album order
+    pub image_urls: Vec<String>,
+    /// Captions of images by their link, if the site has them
+    pub captions: HashMap<String, String>,
+    /// Why images of the album were left out
+    pub errors: Vec<String>,
+}
+
+/// A supported source as shown on the import page
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Source {
+    pub name: &'static str,
+    pub example: &'static str,
+}
+
+/// All available importers.
+///
+/// To support another site, implement `Importer` for it and add it in `new`.
+pub struct Importers {
+    importers: Vec<Box<dyn Importer>>,
+}
+
+impl Importers {
+    pub fn new(config: &Config) -> Self {
+        let mut importers: Vec<Box<dyn Importer>> = vec![
+            Box::new(Imgur::new(&config.imgur)),
+            Box::new(Reddit::new(&config.reddit)),
+        ];
+        // other instances are only known once configured
+        if !config.instances.hosts.is_empty() {
+            importers.push(Box::new(Instances::new(
+                &config.instances,
+                &config.allowed_domains,
+            )));
+        }
+
+        Importers::with(importers)
+    }
+
+    fn with(importers: Vec<Box<dyn Importer>>) -> Self {
+        Importers { importers }
+    }
+
+    /// Get the first importer which can import the album at `url`
+    pub fn find(&self, url: &Url) -> Option<&dyn Importer> {
+        self.importers
+            .iter()
+            .find(|importer| importer.matches(url))
+            .map(Box::as_ref)
+    }
+
+    /// The supported sources in the order they are tried
+    pub fn sources(&self) -> Vec<Source> {
+        self.importers
+            .iter()
+            .map(|importer| Source {
+                name: importer.name(),
+                example: importer.example(),
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for Importers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = self
+            .importers
+            .iter()
+            .map(|importer| importer.name())
+            .collect();
+        f.debug_struct("Importers")
+            .field("importers", &names)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fake(&'static str);
+
+    impl Importer for Fake {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn example(&self) -> &'static str {
+            "https://example.com/album"
+        }
+
+        fn matches(&self, url: &Url) -> bool {
+            url.domain() == Some(self.0)
+        }
+
+        fn album(&self, _url: &Url) -> Result<RemoteAlbum> {
+            Ok(RemoteAlbum::default())
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let importers = Importers::with(vec![Box::new(Fake("a.com")), Box::new(Fake("b.com"))]);
+        let url = |s: &str| s.parse::<Url>().unwrap();
+
+        assert_eq!(
+            importers
+                .find(&url("https://b.com/album"))
+                .map(|i| i.name()),
+            Some("b.com")
+        );
+        assert!(importers.find(&url("https://c.com/album")).is_none());
+        assert_eq!(importers.sources()[0].name, "a.com");
+    }
+}
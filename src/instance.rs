@@ -0,0 +1,252 @@
+use crate::{
+    config::InstancesConfig,
+    fetch::download,
+    handlers::album::validate_url,
+    import::{Importer, RemoteAlbum},
+    manifest::Manifest,
+};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use url::Url;
+
+fn is_token(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Get the link of the manifest of the album a v link points to.
+///
+/// Links to the album, its pages and its manifest are accepted. The instance
+/// may be served below a path, e.g. `https://example.com/v/a/<token>`.
+pub fn manifest_url(url: &Url) -> Result<Url, String> {
+    let segments: Vec<_> = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+
+    let (prefix, token) = match segments.as_slice() {
+        [prefix @ .., "a", token] | [prefix @ .., "a", token, "manifest.json"]
+            if is_token(token) =>
+        {
+            (prefix, token)
+        }
+        _ => return Err(format!("Not a link to an album: {}", url)),
+    };
+
+    let path: String = prefix
+        .iter()
+        .map(|segment| format!("/{}", segment))
+        .collect();
+
+    let mut manifest = url.clone();
+    manifest.set_path(&format!("{}/a/{}/manifest.json", path, token));
+    manifest.set_query(None);
+    manifest.set_fragment(None);
+    Ok(manifest)
+}
+
+/// Returns true if `link` points at a file stored by the instance which
+/// serves the manifest at `manifest_url`
+fn is_stored_file(link: &Url, manifest_url: &Url) -> bool {
+    // stored files are linked relative to the root of the host, even if the
+    // instance is served below a path
+    ["../../f/", "/f/"]
+        .iter()
+        .filter_map(|files| manifest_url.join(files).ok())
+        .any(|files| link.as_str().starts_with(files.as_str()))
+}
+
+/// Imports albums from other v instances through their manifests
+#[derive(Debug)]
+pub struct Instances {
+    hosts: HashSet<String>,
+    max_manifest_size: u64,
+    /// Domains images of imported albums may be linked from, besides the
+    /// instance they are imported from
+    allowed_domains: HashSet<String>,
+}
+
+impl Instances {
+    pub fn new(config: &InstancesConfig, allowed_domains: &HashSet<String>) -> Self {
+        Instances {
+            hosts: config
+                .hosts
+                .iter()
+                .map(|host| host.to_lowercase())
+                .collect(),
+            max_manifest_size: config.max_manifest_size,
+            allowed_domains: allowed_domains.clone(),
+        }
+    }
+
+    fn is_instance(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+
+        match url.port() {
+            Some(port) => self.hosts.contains(&format!("{}:{}", host, port)),
+            None => self.hosts.contains(&host),
+        }
+    }
+}
+
+impl Importer for Instances {
+    fn name(&self) -> &'static str {
+        "Other v instances"
+    }
+
+    fn example(&self) -> &'static str {
+        "https://v.example.com/a/AbCd1234"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        matches!(url.scheme(), "http" | "https") && self.is_instance(url)
+    }
+
+    fn validate(&self, url: &Url) -> Result<(), String> {
+        manifest_url(url).map(|_| ())
+    }
+
+    fn album(&self, url: &Url) -> Result<RemoteAlbum> {
+        let manifest_url = manifest_url(url).map_err(|err| anyhow!(err))?;
+        let json = download(&manifest_url, self.max_manifest_size)?;
+        let manifest = Manifest::from_json(&json)
+            .with_context(|| format!("Could not read {}", manifest_url))?;
+
+        let mut image_urls = Vec::new();
+        let mut captions = HashMap::new();
+        let mut errors = Vec::new();
+        for image in manifest.images {
+            // images stored by the instance have links relative to the manifest
+            let url = image.resolve(Some(&manifest_url)).and_then(|url| {
+                if is_stored_file(&url, &manifest_url) {
+                    return Ok(url);
+                }
+                validate_url(&self.allowed_domains, url.as_str()).map_err(|err| err.1)
+            });
+            let link = match url {
+                Ok(url) => url.to_string(),
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+
+            let caption = image.caption.as_deref().map(str::trim);
+            if let Some(caption) = caption.filter(|caption| !caption.is_empty()) {
+                captions.insert(link.clone(), caption.to_string());
+            }
+            image_urls.push(link);
+        }
+
+        Ok(RemoteAlbum {
+            title: manifest.title,
+            image_urls,
+            captions,
+            errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    const MANIFEST: &str = r#"{
+        "version": 1,
+        "title": "Cats",
+        "images": [
+            {"url": "/f/first.png", "caption": "First"},
+            {"url": "https://i.imgur.com/second.png", "source": "https://example.com/second.png"},
+            {"url": "http://127.0.0.1:5432/third.png"}
+        ]
+    }"#;
+
+    fn parse(url: &str) -> Result<String, String> {
+        manifest_url(&url.parse().unwrap()).map(|url| url.to_string())
+    }
+
+    #[test]
+    fn test_manifest_url() {
+        let manifest = || Ok("https://v.example.com/a/AbCd1234/manifest.json".to_string());
+
+        assert_eq!(parse("https://v.example.com/a/AbCd1234"), manifest());
+        assert_eq!(parse("https://v.example.com/a/AbCd1234/"), manifest());
+        assert_eq!(
+            parse("https://v.example.com/a/AbCd1234?page=2#top"),
+            manifest()
+        );
+        assert_eq!(
+            parse("https://v.example.com/a/AbCd1234/manifest.json"),
+            manifest()
+        );
+        assert_eq!(
+            parse("https://example.com/v/a/AbCd1234"),
+            Ok("https://example.com/v/a/AbCd1234/manifest.json".to_string())
+        );
+
+        assert!(parse("https://v.example.com/").is_err());
+        assert!(parse("https://v.example.com/a/").is_err());
+        assert!(parse("https://v.example.com/a/AbCd1234/edit").is_err());
+        assert!(parse("https://v.example.com/a/Ab-Cd").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let instances = Instances::new(
+            &InstancesConfig {
+                hosts: vec!["v.example.com".to_string(), "localhost:8000".to_string()]
+                    .into_iter()
+                    .collect(),
+                ..InstancesConfig::default()
+            },
+            &HashSet::new(),
+        );
+        let matches = |url: &str| instances.matches(&url.parse().unwrap());
+
+        assert!(matches("https://V.Example.com/a/AbCd1234"));
+        assert!(matches("http://localhost:8000/a/AbCd1234"));
+        assert!(!matches("http://localhost/a/AbCd1234"));
+        assert!(!matches("https://example.com/a/AbCd1234"));
+        assert!(!matches("ftp://v.example.com/a/AbCd1234"));
+    }
+
+    #[test]
+    fn test_album() {
+        let base = mock::serve(vec![mock::json(MANIFEST, &[])]);
+        let allowed_domains = vec!["i.imgur.com".to_string()].into_iter().collect();
+        let album = Instances::new(&InstancesConfig::default(), &allowed_domains)
+            .album(&base.join("a/AbCd1234").unwrap())
+            .unwrap();
+
+        let first = base.join("f/first.png").unwrap().to_string();
+        assert_eq!(album.title.as_deref(), Some("Cats"));
+        assert_eq!(
+            album.image_urls,
+            vec![first.as_str(), "https://i.imgur.com/second.png"]
+        );
+        assert_eq!(
+            album.captions.get(&first).map(String::as_str),
+            Some("First")
+        );
+        assert_eq!(album.captions.len(), 1);
+        assert_eq!(album.errors.len(), 1);
+        assert!(album.errors[0].contains("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_is_stored_file() {
+        let manifest: Url = "https://example.com/v/a/AbCd1234/manifest.json"
+            .parse()
+            .unwrap();
+        let stored = |link: &str| is_stored_file(&link.parse().unwrap(), &manifest);
+
+        assert!(stored("https://example.com/f/first.png"));
+        assert!(stored("https://example.com/v/f/first.png"));
+        assert!(!stored("https://example.com/a/first.png"));
+        assert!(!stored("http://example.com/f/first.png"));
+        assert!(!stored("https://example.com.evil.com/f/first.png"));
+    }
+}
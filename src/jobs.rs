@@ -0,0 +1,456 @@
+use crate::{
+    config::Config,
+    handlers::album::{import_warnings, remote_metadata, save_metadata, validate_image},
+    import::{Importers, RemoteAlbum},
+    media::{resolve_gifv, MediaKind},
+    metadata,
+    models::{Album, Image, ImportJob},
+    storage::{file_name, Storage},
+};
+use anyhow::{Context, Result};
+use diesel::{
+    deserialize::{self, FromSql},
+    pg::Pg,
+    serialize::{self, Output, ToSql},
+    sql_types::Varchar,
+    Connection, PgConnection, RunQueryDsl,
+};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    io::Write,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use url::Url;
+
+/// Time to wait before looking for queued jobs again once all are done
+const IDLE_DELAY: Duration = Duration::from_secs(2);
+/// Time to wait before reconnecting after a database error
+const ERROR_DELAY: Duration = Duration::from_secs(30);
+/// Time between looking for imported albums which are due for a sync
+const SYNC_CHECK_DELAY: Duration = Duration::from_secs(60);
+/// Time after which a running job which made no progress is considered to be
+/// interrupted. A job is updated after every image, so this is much longer
+/// than it takes to add one.
+const STALE_JOB_AGE: Duration = Duration::from_secs(10 * 60);
+/// Time between looking for interrupted jobs
+const STALE_CHECK_DELAY: Duration = Duration::from_secs(60);
+/// Number of images whose missing metadata is computed between two jobs
+const METADATA_BATCH_SIZE: i64 = 10;
+/// Time to wait before looking for images with missing metadata again once
+/// all are done
+const METADATA_CHECK_DELAY: Duration = Duration::from_secs(60);
+
+/// Progress of an import job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Varchar"]
+pub enum JobStatus {
+    /// Waiting for the worker
+    Queued,
+    /// Images are being added
+    Running,
+    /// All images were processed, some may have been skipped
+    Done,
+    /// The album could not be imported at all
+    Failed,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for JobStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<Varchar, Pg>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Varchar, Pg> for JobStatus {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match <String as FromSql<Varchar, Pg>>::from_sql(bytes)?.as_str() {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "done" => Ok(JobStatus::Done),
+            "failed" => Ok(JobStatus::Failed),
+            status => Err(format!("Unknown job status `{}`", status).into()),
+        }
+    }
+}
+
+/// Everything the worker needs to add images to an album
+struct Worker {
+    config: Config,
+    storage: Storage,
+    importers: Importers,
+}
+
+/// Run queued import jobs in a background thread
+pub fn spawn(database_url: String, config: Config) -> Result<()> {
+    let worker = Worker {
+        storage: Storage::new(&config.storage)?,
+        importers: Importers::new(&config),
+        config,
+    };
+
+    thread::Builder::new()
+        .name("import".into())
+        .spawn(move || loop {
+            if let Err(err) = worker.run(&database_url) {
+                warn!("Could not run import jobs: {:#}", err);
+                thread::sleep(ERROR_DELAY);
+            }
+        })
+        .context("Could not spawn import thread")?;
+    Ok(())
+}
+
+impl Worker {
+    /// Run jobs until the database connection fails
+    fn run(&self, database_url: &str) -> Result<()> {
+        let conn =
+            PgConnection::establish(database_url).context("Could not connect to database")?;
+
+        let mut next_stale_check = Instant::now();
+        let mut next_sync_check = Instant::now();
+        let mut next_metadata_check = Instant::now();
+        let mut unreadable = Vec::new();
+
+        loop {
+            // jobs of other workers are only taken over once they stopped
+            // making progress
+            if Instant::now() >= next_stale_check {
+                let resumed = ImportJob::requeue_stale(&conn, SystemTime::now() - STALE_JOB_AGE)?;
+                if resumed > 0 {
+                    info!("Resuming {} interrupted imports", resumed);
+                }
+                next_stale_check = Instant::now() + STALE_CHECK_DELAY;
+            }
+
+            if self.config.sync.enabled && Instant::now() >= next_sync_check {
+                self.queue_syncs(&conn)?;
+                next_sync_check = Instant::now() + SYNC_CHECK_DELAY;
+            }
+
+            match ImportJob::claim_next(&conn)? {
+                Some(job) => match job.links.clone() {
+                    Some(links) => self.add_links(&conn, job, &links)?,
+                    None => self.import(&conn, job)?,
+                },
+                // placeholders and hashes of remote images are computed
+                // while there is nothing to import
+                None if Instant::now() >= next_metadata_check => {
+                    let checked = metadata::backfill(
+                        &conn,
+                        &self.storage,
+                        self.config.storage.max_download_size,
+                        &mut unreadable,
+                        METADATA_BATCH_SIZE,
+                    )?;
+                    if checked == 0 {
+                        next_metadata_check = Instant::now() + METADATA_CHECK_DELAY;
+                    }
+                }
+                None => thread::sleep(IDLE_DELAY),
+            }
+        }
+    }
+
+    /// Queue a sync of every imported album which was not synced recently
+    fn queue_syncs(&self, conn: &PgConnection) -> Result<()> {
+        let synced_before = SystemTime::now() - Duration::from_secs(self.config.sync.interval);
+
+        for album in Album::due_for_sync(conn, synced_before)? {
+            if let Some(source) = &album.source {
+                debug!("Syncing album {} with {}", album.token, source);
+                ImportJob::new(conn, album.id, source)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the images of the source album which are not in the album yet
+    /// after the existing images, and flag the images which were removed
+    /// from the source album.
+    ///
+    /// This imports a new album, syncs an imported one and resumes an
+    /// interrupted job alike. Images without an upstream link were added by
+    /// the owner and are never touched, unless the source album has them too.
+    fn import(&self, conn: &PgConnection, mut job: ImportJob) -> Result<()> {
+        let url = job.url.clone().unwrap_or_default();
+        debug!("Importing {} (job {})", url, job.id);
+
+        let album: Album = Album::by_id(job.album_id)
+            .get_result(conn)
+            .context("Could not get album of import job")?;
+
+        let RemoteAlbum {
+            title,
+            image_urls: links,
+            captions,
+            errors,
+        } = match self.remote_album(&url) {
+            Ok(remote) => remote,
+            Err(err) => {
+                job.errors.push(err);
+                job.status = JobStatus::Failed;
+                return job.save(conn);
+            }
+        };
+
+        // a title entered by the owner is kept
+        if let (None, Some(title)) = (&album.title, title) {
+            album.set_title(conn, &title)?;
+        }
+
+        let adopted = album.adopt_upstream(conn, &links)?;
+        if adopted > 0 {
+            debug!(
+                "Linked {} images of album {} to {}",
+                adopted, album.token, url
+            );
+        }
+
+        let existing: HashSet<_> = album.upstream_links(conn)?.into_iter().collect();
+        let pending: Vec<_> = links
+            .iter()
+            .filter(|link| !existing.contains(*link))
+            .collect();
+
+        // the counts of an interrupted run are replaced, as its images are not pending anymore
+        job.total = Some(pending.len() as i32);
+        job.fetched = 0;
+        job.inserted = 0;
+        job.skipped = 0;
+        job.errors = errors;
+        job.removed = album.flag_removed_upstream(conn, &links)? as i32;
+        job.save(conn)?;
+
+        let start = album.image_count(conn)? as i32;
+        for link in pending {
+            let caption = captions.get(link).map(String::as_str);
+
+            // an image is only added together with the progress, so the
+            // counts of an interrupted job match its images
+            conn.transaction::<_, anyhow::Error, _>(|| {
+                let index = start + job.inserted;
+                match self.add_image(conn, &album, link, true, caption, index) {
+                    Ok(()) => {
+                        job.fetched += 1;
+                        job.inserted += 1;
+                    }
+                    Err((fetched, err)) => {
+                        job.fetched += fetched as i32;
+                        job.skipped += 1;
+                        job.errors.push(err);
+                    }
+                }
+                job.save(conn)
+            })?;
+        }
+
+        self.finish(conn, &album, &mut job)?;
+
+        info!(
+            "Imported {} of {} new images from {}",
+            job.inserted,
+            job.total.unwrap_or_default(),
+            url
+        );
+
+        Ok(())
+    }
+
+    /// Add the images at `links`, which the user gave as a list, e.g. pasted
+    /// or picked from a web page, from the position of the job on.
+    ///
+    /// A resumed job continues after the last image it processed.
+    fn add_links(&self, conn: &PgConnection, mut job: ImportJob, links: &[String]) -> Result<()> {
+        debug!("Adding {} images (job {})", links.len(), job.id);
+
+        let album: Album = Album::by_id(job.album_id)
+            .get_result(conn)
+            .context("Could not get album of import job")?;
+
+        job.total = Some(links.len() as i32);
+        job.save(conn)?;
+
+        for (number, link) in links.iter().enumerate().skip(job.processed() as usize) {
+            let caption = job
+                .captions
+                .get(number)
+                .filter(|caption| !caption.is_empty())
+                .cloned();
+
+            // like for imports, an image is only added together with the progress
+            conn.transaction::<_, anyhow::Error, _>(|| {
+                // images may have been deleted since the job was queued
+                let count = album.image_count(conn)? as i32;
+                let index = (job.insert_at + job.inserted).min(count);
+                // links to stored files are only queued for restored manifests
+                let added = match file_name(link) {
+                    Some(name) => self.add_file(conn, &album, name, caption.as_deref(), index),
+                    None => self.add_image(conn, &album, link, false, caption.as_deref(), index),
+                };
+                match added {
+                    Ok(()) => {
+                        job.fetched += 1;
+                        job.inserted += 1;
+                    }
+                    Err((fetched, err)) => {
+                        job.fetched += fetched as i32;
+                        job.skipped += 1;
+                        job.errors.push(err);
+                    }
+                }
+                job.save(conn)
+            })?;
+        }
+
+        self.finish(conn, &album, &mut job)?;
+
+        info!(
+            "Added {} of {} images to album {}",
+            job.inserted,
+            links.len(),
+            album.token
+        );
+
+        Ok(())
+    }
+
+    /// Warn about duplicates in the album and mark the job as done
+    fn finish(&self, conn: &PgConnection, album: &Album, job: &mut ImportJob) -> Result<()> {
+        let hashes = album.image_hashes(conn).unwrap_or_else(|err| {
+            warn!("Could not look for duplicates: {:#}", err);
+            Vec::new()
+        });
+        job.warnings = import_warnings(conn, album, &hashes);
+        job.status = JobStatus::Done;
+        job.save(conn)
+    }
+
+    /// Get the title and the links of all images of the album at `url`
+    fn remote_album(&self, url: &str) -> Result<RemoteAlbum, String> {
+        let url: Url = url
+            .parse()
+            .map_err(|err| format!("Invalid album link {}: {}", url, err))?;
+
+        let importer = self
+            .importers
+            .find(&url)
+            .ok_or_else(|| format!("Unsupported album link: {}", url))?;
+
+        let remote = importer
+            .album(&url)
+            .map_err(|err| format!("Could not get album images: {:#}", err))?;
+
+        if remote.image_urls.len() > i32::MAX as usize {
+            return Err(format!(
+                "Album has too many images (more than {})",
+                i32::MAX
+            ));
+        }
+
+        Ok(remote)
+    }
+
+    /// Add the image at `link` with its `caption` at position `index`, moving
+    /// the images from there on back. If `upstream` is set, the image is
+    /// linked to the image of the source album at `link`.
+    ///
+    /// On failure returns whether the image was fetched and why it was skipped.
+    fn add_image(
+        &self,
+        conn: &PgConnection,
+        album: &Album,
+        link: &str,
+        upstream: bool,
+        caption: Option<&str>,
+        index: i32,
+    ) -> Result<(), (bool, String)> {
+        let url = link
+            .parse()
+            .map(resolve_gifv)
+            .map_err(|err| (false, format!("Invalid image link {}: {}", link, err)))?;
+        let probe = validate_image(&url, self.config.storage.max_download_size)
+            .map_err(|err| (false, err.1))?;
+
+        // the images are only moved if the image could be added
+        let image = conn
+            .transaction(|| {
+                album.increase_index(conn, index)?;
+                if upstream {
+                    album.add_upstream_image(conn, &self.storage, url.as_str(), link, index)
+                } else {
+                    album.add_image(conn, &self.storage, url.as_str(), index)
+                }
+            })
+            .map_err(|err| (true, format!("Could not add image {}: {:#}", url, err)))?;
+        let metadata = remote_metadata(&self.storage, &image, &url, &probe);
+        save_metadata(conn, &image, &metadata);
+
+        // the worker can afford to download whole images, so duplicates are
+        // found before the import is done
+        if image.file.is_none() && metadata.kind == Some(MediaKind::Image) {
+            let max_size = self.config.storage.max_download_size;
+            if let Err(err) = metadata::complete(conn, &image, &self.storage, max_size) {
+                warn!("Could not read image {}: {:#}", image.token, err);
+            }
+        }
+
+        set_caption(conn, &image, caption);
+
+        Ok(())
+    }
+
+    /// Add the file `name` stored by this instance like [`Worker::add_image`]
+    /// adds a remote image
+    fn add_file(
+        &self,
+        conn: &PgConnection,
+        album: &Album,
+        name: &str,
+        caption: Option<&str>,
+        index: i32,
+    ) -> Result<(), (bool, String)> {
+        let data = self
+            .storage
+            .reuse(name)
+            .map_err(|err| (false, format!("{:#}", err)))?;
+
+        let image = conn
+            .transaction(|| {
+                album.increase_index(conn, index)?;
+                album.add_file(conn, name, index)
+            })
+            .map_err(|err| (false, format!("Could not add image {}: {:#}", name, err)))?;
+        save_metadata(conn, &image, &metadata::from_data(&data));
+        set_caption(conn, &image, caption);
+
+        Ok(())
+    }
+}
+
+/// Set the `caption` of a newly added image, which is only logged if it fails
+fn set_caption(conn: &PgConnection, image: &Image, caption: Option<&str>) {
+    if caption.is_some() {
+        if let Err(err) = image.set_caption(conn, caption) {
+            warn!("Could not set caption of image {}: {:#}", image.token, err);
+        }
+    }
+}
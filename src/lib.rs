@@ -8,21 +8,46 @@ extern crate diesel;
 #[macro_use]
 extern crate rocket_contrib;
 
+mod avif;
 mod config;
 mod deletion_token;
+mod export;
+mod fetch;
 mod imgur;
+mod import;
+mod instance;
+mod jobs;
+mod link_check;
+mod manifest;
+mod media;
+mod metadata;
+#[cfg(test)]
+mod mock;
+mod phash;
+mod reddit;
+mod sanitize;
 mod schema;
+mod scrape;
+mod storage;
+mod thumbnails;
+mod transforms;
+mod unpack;
+mod upload;
 
 pub mod handlers;
 pub mod models;
 
 use crate::handlers::*;
 use config::Config;
+use import::Importers;
 use lazy_static::lazy_static;
 use log::{error, info};
 use rocket::{catchers, fairing::AdHoc, http::Header, routes, Rocket};
 use rocket_contrib::{helmet::SpaceHelmet, templates::Template};
 use self_update::cargo_crate_version;
+use storage::Storage;
+use thumbnails::Thumbnails;
+use transforms::Transforms;
 
 lazy_static! {
     static ref STATIC_HEADERS: Vec<Header<'static>> = vec![
@@ -50,6 +75,9 @@ pub fn rocket() -> Rocket {
                 static_files::favicon,
                 static_files::styles,
                 static_files::background,
+                files::get,
+                thumbs::get,
+                handlers::transforms::get,
             ],
         )
         .mount(
@@ -58,11 +86,23 @@ pub fn rocket() -> Rocket {
                 album::get,
                 album::head,
                 album::new,
+                album::upload,
                 album::import,
+                album::import_status,
+                album::resync,
+                album::scrape,
+                album::pick,
+                album::import_manifest,
+                album::upload_manifest,
                 album::get_auth,
                 album::post_auth,
                 album::get_edit,
                 album::post_edit,
+                album::get_broken,
+                album::get_duplicates,
+                album::download,
+                album::manifest,
+                album::post_upload,
             ],
         )
         .attach(SpaceHelmet::default())
@@ -76,13 +116,97 @@ pub fn rocket() -> Rocket {
         .attach(Template::fairing())
         .attach(AdHoc::on_attach("V Config", |rocket| {
             match Config::load("./config.toml") {
-                Ok(c) => Ok(rocket.manage(c)),
+                Ok(c) => {
+                    let importers = Importers::new(&c);
+                    Ok(rocket.manage(c).manage(importers))
+                }
                 Err(err) => {
                     error!("Could not load config: {}", err);
                     Err(rocket)
                 }
             }
         }))
+        .attach(AdHoc::on_attach("V Storage", |rocket| {
+            let storage = match rocket.state::<Config>() {
+                Some(config) => Storage::new(&config.storage).and_then(|storage| {
+                    let directory = &config.storage.directory;
+                    let thumbnails = Thumbnails::new(&config.thumbnails, directory)?;
+                    let transforms = Transforms::new(&config.transforms, directory)?;
+                    Ok((storage, thumbnails, transforms))
+                }),
+                None => return Err(rocket),
+            };
+
+            match storage {
+                Ok((s, t, tr)) => Ok(rocket.manage(s).manage(t).manage(tr)),
+                Err(err) => {
+                    error!("Could not open storage: {}", err);
+                    Err(rocket)
+                }
+            }
+        }))
+}
+
+/// Remove stored files which are not used by any image anymore
+pub fn gc(rocket: &Rocket, conn: &diesel::PgConnection) -> anyhow::Result<()> {
+    let storage = rocket
+        .state::<Storage>()
+        .ok_or_else(|| anyhow::anyhow!("Storage is not available"))?;
+    storage.collect_garbage(conn)?;
+
+    rocket
+        .state::<Transforms>()
+        .ok_or_else(|| anyhow::anyhow!("Transforms are not available"))?
+        .collect_garbage(storage)?;
+
+    let tokens = models::Image::all_tokens(conn)?;
+    rocket
+        .state::<Thumbnails>()
+        .ok_or_else(|| anyhow::anyhow!("Thumbnails are not available"))?
+        .collect_garbage(&tokens.into_iter().collect())
+}
+
+/// Compute missing metadata of images
+pub fn update_metadata(rocket: &Rocket, conn: &diesel::PgConnection) -> anyhow::Result<()> {
+    let config = rocket
+        .state::<Config>()
+        .ok_or_else(|| anyhow::anyhow!("Config is not available"))?;
+    let storage = rocket
+        .state::<Storage>()
+        .ok_or_else(|| anyhow::anyhow!("Storage is not available"))?;
+
+    metadata::backfill_all(conn, storage, config.storage.max_download_size)
+}
+
+/// Check the links of all remote images which are due
+pub fn check_links(rocket: &Rocket, conn: &diesel::PgConnection) -> anyhow::Result<()> {
+    let config = rocket
+        .state::<Config>()
+        .ok_or_else(|| anyhow::anyhow!("Config is not available"))?;
+
+    link_check::check_all(conn, &config.link_check)
+}
+
+/// Start checking the links of remote images in the background, if enabled
+pub fn spawn_link_checker(rocket: &Rocket, database_url: &str) -> anyhow::Result<()> {
+    let config = rocket
+        .state::<Config>()
+        .ok_or_else(|| anyhow::anyhow!("Config is not available"))?;
+
+    if config.link_check.enabled {
+        link_check::spawn(database_url.to_string(), config.link_check)?;
+    }
+
+    Ok(())
+}
+
+/// Start running queued album imports in the background
+pub fn spawn_import_worker(rocket: &Rocket, database_url: &str) -> anyhow::Result<()> {
+    let config = rocket
+        .state::<Config>()
+        .ok_or_else(|| anyhow::anyhow!("Config is not available"))?;
+
+    jobs::spawn(database_url.to_string(), config.clone())
 }
 
 pub fn update() -> anyhow::Result<()> {
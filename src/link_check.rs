@@ -0,0 +1,314 @@
+use crate::{
+    config::LinkCheckConfig,
+    fetch::{check, LinkCheck},
+    models::{Image, LINK_UNREACHABLE},
+};
+use anyhow::{Context, Result};
+use diesel::{Connection, PgConnection};
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use url::Url;
+
+/// Number of images loaded from the database at once
+const BATCH_SIZE: i64 = 100;
+/// Time to wait before looking for due images again once all are checked
+const IDLE_DELAY: Duration = Duration::from_secs(10 * 60);
+/// Time to wait before checking a rate limited link again if the server
+/// does not say how long
+const RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Shortest time to wait before checking a link again the server asked to
+/// come back later for
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Result of checking one link
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    /// HTTP status the link is served with, or [`LINK_UNREACHABLE`]
+    Status(i32),
+    /// The server is rate limiting or temporarily down, so it is unknown
+    /// whether the link works until it is checked again after this time
+    RetryLater(Duration),
+}
+
+/// Keep checking the links of remote images in a background thread
+pub fn spawn(database_url: String, config: LinkCheckConfig) -> Result<()> {
+    thread::Builder::new()
+        .name("link-check".into())
+        .spawn(move || run(&database_url, &config))
+        .context("Could not spawn link check thread")?;
+    Ok(())
+}
+
+fn run(database_url: &str, config: &LinkCheckConfig) {
+    let mut limiter = RateLimiter::new(Duration::from_millis(config.domain_delay));
+
+    loop {
+        let checked = PgConnection::establish(database_url)
+            .context("Could not connect to database")
+            .and_then(|conn| check_batch(&conn, config, &mut limiter));
+
+        match checked {
+            Ok(0) => thread::sleep(IDLE_DELAY),
+            Ok(count) => debug!("Checked {} links", count),
+            Err(err) => {
+                warn!("Could not check links: {:#}", err);
+                thread::sleep(IDLE_DELAY);
+            }
+        }
+    }
+}
+
+/// Check all remote images which are due once
+pub fn check_all(conn: &PgConnection, config: &LinkCheckConfig) -> Result<()> {
+    let mut limiter = RateLimiter::new(Duration::from_millis(config.domain_delay));
+    let mut total = 0;
+
+    loop {
+        match check_batch(conn, config, &mut limiter)? {
+            0 => break,
+            count => total += count,
+        }
+    }
+
+    info!("Checked {} links", total);
+
+    Ok(())
+}
+
+/// Check the next batch of due images and return how many were checked
+fn check_batch(
+    conn: &PgConnection,
+    config: &LinkCheckConfig,
+    limiter: &mut RateLimiter,
+) -> Result<usize> {
+    let interval = Duration::from_secs(config.interval);
+    let checked_before = SystemTime::now() - interval;
+    let images = Image::due_for_link_check(conn, checked_before, BATCH_SIZE)?;
+    let count = images.len();
+
+    limiter.forget_expired();
+
+    // alternate between domains so the delay of one domain does not block the others
+    for image in interleave(images, |image| domain(&image.url)) {
+        let now = SystemTime::now();
+        match check_image(&image, limiter) {
+            Outcome::Status(status) => image.set_link_status(conn, status, now)?,
+            Outcome::RetryLater(delay) => {
+                // pretend the last check was earlier, so the image is due
+                // again after `delay` instead of the whole interval
+                let delay = delay.max(MIN_RETRY_DELAY).min(interval);
+                image.set_link_checked_at(conn, now - interval + delay)?;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+fn check_image(image: &Image, limiter: &mut RateLimiter) -> Outcome {
+    let url: Url = match image.url.parse() {
+        Ok(url) => url,
+        Err(err) => {
+            debug!("Invalid url {}: {}", image.url, err);
+            return Outcome::Status(LINK_UNREACHABLE);
+        }
+    };
+
+    let domain = url.host_str().unwrap_or_default();
+    if let Some(delay) = limiter.backing_off(domain) {
+        return Outcome::RetryLater(delay);
+    }
+
+    limiter.wait(domain);
+
+    match check(&url) {
+        Ok(check) => {
+            let outcome = outcome(&check);
+            if let Outcome::RetryLater(delay) = outcome {
+                debug!("{} asked to retry after {:?}", domain, delay);
+                limiter.back_off(domain, delay);
+            }
+            outcome
+        }
+        Err(err) => {
+            debug!("{:#}", err);
+            Outcome::Status(LINK_UNREACHABLE)
+        }
+    }
+}
+
+/// Rate limited responses and temporary outages with a known end say
+/// nothing about the link itself
+fn outcome(check: &LinkCheck) -> Outcome {
+    match (check.status, check.retry_after) {
+        (StatusCode::TOO_MANY_REQUESTS, delay) => Outcome::RetryLater(delay.unwrap_or(RETRY_DELAY)),
+        (StatusCode::SERVICE_UNAVAILABLE, Some(delay)) => Outcome::RetryLater(delay),
+        (status, _) => Outcome::Status(i32::from(status.as_u16())),
+    }
+}
+
+fn domain(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+}
+
+/// Reorder `items` so consecutive items have different keys where possible,
+/// keeping the order of items with the same key
+fn interleave<T, K, F>(items: Vec<T>, key: F) -> Vec<T>
+where
+    K: Eq + Hash,
+    F: Fn(&T) -> K,
+{
+    let mut positions = HashMap::new();
+    let mut groups: Vec<Vec<T>> = Vec::new();
+
+    for item in items {
+        let next = groups.len();
+        let group = *positions.entry(key(&item)).or_insert(next);
+        if group == groups.len() {
+            groups.push(Vec::new());
+        }
+        groups[group].push(item);
+    }
+
+    let mut groups: Vec<_> = groups.into_iter().map(Vec::into_iter).collect();
+    let mut result = Vec::new();
+    loop {
+        let len = result.len();
+        result.extend(groups.iter_mut().filter_map(Iterator::next));
+        if result.len() == len {
+            return result;
+        }
+    }
+}
+
+/// Limits requests to each domain to one per `delay`, and stops requests to
+/// domains which asked to come back later
+#[derive(Debug)]
+struct RateLimiter {
+    delay: Duration,
+    next: HashMap<String, Instant>,
+    paused: HashMap<String, Instant>,
+}
+
+impl RateLimiter {
+    fn new(delay: Duration) -> Self {
+        RateLimiter {
+            delay,
+            next: HashMap::new(),
+            paused: HashMap::new(),
+        }
+    }
+
+    /// Do not send requests to `domain` for `delay`
+    fn back_off(&mut self, domain: &str, delay: Duration) {
+        self.paused
+            .insert(domain.to_string(), Instant::now() + delay);
+    }
+
+    /// Get the remaining time requests to `domain` are paused for
+    fn backing_off(&self, domain: &str) -> Option<Duration> {
+        let until = *self.paused.get(domain)?;
+        let now = Instant::now();
+        (until > now).then(|| until - now)
+    }
+
+    /// Block until a request to `domain` is allowed
+    fn wait(&mut self, domain: &str) {
+        if let Some(&next) = self.next.get(domain) {
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+        }
+
+        self.next
+            .insert(domain.to_string(), Instant::now() + self.delay);
+    }
+
+    /// Drop domains which may be requested again right away
+    fn forget_expired(&mut self) {
+        let now = Instant::now();
+        self.next.retain(|_, next| *next > now);
+        self.paused.retain(|_, until| *until > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        let urls = vec!["a/1", "a/2", "a/3", "b/1", "c/1", "b/2"];
+        let interleaved = interleave(urls, |url| url.split('/').next().unwrap().to_string());
+
+        assert_eq!(interleaved, vec!["a/1", "b/1", "c/1", "a/2", "b/2", "a/3"]);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(Duration::from_millis(50));
+        let start = Instant::now();
+
+        limiter.wait("a");
+        limiter.wait("b");
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        limiter.wait("a");
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_back_off() {
+        let mut limiter = RateLimiter::new(Duration::from_millis(0));
+
+        limiter.back_off("a", Duration::from_secs(60));
+        assert!(limiter.backing_off("a").unwrap() > Duration::from_secs(59));
+        assert_eq!(limiter.backing_off("b"), None);
+
+        limiter.back_off("b", Duration::from_millis(0));
+        limiter.forget_expired();
+        assert_eq!(limiter.backing_off("b"), None);
+    }
+
+    #[test]
+    fn test_outcome() {
+        let outcome = |status, retry_after| {
+            outcome(&LinkCheck {
+                status,
+                retry_after,
+            })
+        };
+        let minute = Duration::from_secs(60);
+
+        assert_eq!(outcome(StatusCode::OK, None), Outcome::Status(200));
+        assert_eq!(
+            outcome(StatusCode::NOT_FOUND, Some(minute)),
+            Outcome::Status(404)
+        );
+        assert_eq!(
+            outcome(StatusCode::TOO_MANY_REQUESTS, Some(minute)),
+            Outcome::RetryLater(minute)
+        );
+        assert_eq!(
+            outcome(StatusCode::TOO_MANY_REQUESTS, None),
+            Outcome::RetryLater(RETRY_DELAY)
+        );
+        assert_eq!(
+            outcome(StatusCode::SERVICE_UNAVAILABLE, Some(minute)),
+            Outcome::RetryLater(minute)
+        );
+        assert_eq!(
+            outcome(StatusCode::SERVICE_UNAVAILABLE, None),
+            Outcome::Status(503)
+        );
+    }
+}
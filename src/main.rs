@@ -35,6 +35,24 @@ pub fn main() -> Result<()> {
     let conn = PgConnection::establish(db_config.url)?;
     embedded_migrations::run(&conn)?;
 
+    if std::env::args().nth(1).as_deref() == Some("gc") {
+        info!("Collecting garbage");
+        return v::gc(&rocket, &conn);
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("update-metadata") {
+        info!("Updating image metadata");
+        return v::update_metadata(&rocket, &conn);
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("check-links") {
+        info!("Checking links");
+        return v::check_links(&rocket, &conn);
+    }
+
+    v::spawn_link_checker(&rocket, db_config.url)?;
+    v::spawn_import_worker(&rocket, db_config.url)?;
+
     rocket.launch();
 
     Ok(())
@@ -0,0 +1,194 @@
+use crate::models::{Album, Image};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Version of the manifest format written by this version of v.
+///
+/// Bump it when a change would make older versions misread a manifest.
+/// Adding optional fields does not need a new version.
+pub const VERSION: u32 = 1;
+
+/// Description of an album which can be exported and imported again, e.g.
+/// to back up an album or to move it to another instance
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Images in album order
+    pub images: Vec<ManifestImage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestImage {
+    /// Link of the image. Images stored by the exporting instance have a
+    /// relative link if it has no public link configured.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    /// Original link of an image which was mirrored or imported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub metadata: ManifestMetadata,
+}
+
+/// Properties of an image's content as known to the exporting instance.
+///
+/// Only informational, the importing instance looks at the images itself.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ManifestMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Size in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<i64>,
+}
+
+/// Just the version, to reject newer manifests before reading the rest
+#[derive(Debug, Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+impl Manifest {
+    /// Describe `album` with its `images`, which must be in album order.
+    ///
+    /// Links of stored images are made absolute with `base`, the link of the
+    /// exporting instance, so they can be imported elsewhere.
+    pub fn new(album: &Album, images: &[Image], base: Option<&Url>) -> Self {
+        Manifest {
+            version: VERSION,
+            title: album.title.clone(),
+            images: images
+                .iter()
+                .map(|image| ManifestImage::new(image, base))
+                .collect(),
+        }
+    }
+
+    /// Read a manifest written by this or an older version of v
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let Versioned { version } = serde_json::from_slice(json).context("Invalid manifest")?;
+        if version == 0 || version > VERSION {
+            bail!(
+                "Unsupported manifest version {} (supported are up to {})",
+                version,
+                VERSION
+            );
+        }
+
+        let manifest: Manifest = serde_json::from_slice(json).context("Invalid manifest")?;
+        if manifest.images.is_empty() {
+            bail!("Manifest has no images");
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl ManifestImage {
+    fn new(image: &Image, base: Option<&Url>) -> Self {
+        let url = base
+            .and_then(|base| base.join(&image.url).ok())
+            .map_or_else(|| image.url.clone(), Url::into_string);
+
+        ManifestImage {
+            url,
+            caption: image.caption.clone(),
+            source: image.source.clone(),
+            metadata: ManifestMetadata {
+                width: image.width,
+                height: image.height,
+                content_type: image.content_type.clone(),
+                size: image.size,
+            },
+        }
+    }
+
+    /// Get the absolute link of the image, resolving a relative link against
+    /// the location of the manifest if it is known
+    pub fn resolve(&self, base: Option<&Url>) -> Result<Url, String> {
+        match base {
+            Some(base) => base.join(&self.url),
+            None => Url::parse(&self.url),
+        }
+        .map_err(|err| format!("Invalid image link {}: {}", self.url, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(url: &str) -> ManifestImage {
+        ManifestImage {
+            url: url.to_string(),
+            caption: None,
+            source: None,
+            metadata: ManifestMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn test_from_json() {
+        let manifest = Manifest::from_json(
+            br#"{
+                "version": 1,
+                "title": "Cats",
+                "images": [
+                    {"url": "https://i.imgur.com/a.png", "caption": "First"},
+                    {"url": "/f/b.png", "metadata": {"width": 40, "height": 30}},
+                    {"url": "https://i.imgur.com/c.png", "future": "ignored"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.title.as_deref(), Some("Cats"));
+        assert_eq!(manifest.images.len(), 3);
+        assert_eq!(manifest.images[0].caption.as_deref(), Some("First"));
+        assert_eq!(manifest.images[1].metadata.width, Some(40));
+        assert_eq!(manifest.images[2].url, "https://i.imgur.com/c.png");
+    }
+
+    #[test]
+    fn test_from_json_invalid() {
+        let err = |json: &str| {
+            Manifest::from_json(json.as_bytes())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(err(r#"{"version": 2, "whatever": true}"#).contains("Unsupported"));
+        assert!(err(r#"{"version": 0, "images": []}"#).contains("Unsupported"));
+        assert!(err(r#"{"version": 1, "images": []}"#).contains("no images"));
+        assert!(err(r#"{"images": []}"#).contains("Invalid"));
+        assert!(err(r#"{"version": 1}"#).contains("Invalid"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let base: Url = "https://v.example.com/a/token/manifest.json"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            image("/f/b.png").resolve(Some(&base)).unwrap().as_str(),
+            "https://v.example.com/f/b.png"
+        );
+        assert_eq!(
+            image("https://i.imgur.com/a.png")
+                .resolve(Some(&base))
+                .unwrap()
+                .as_str(),
+            "https://i.imgur.com/a.png"
+        );
+        assert!(image("/f/b.png").resolve(None).is_err());
+    }
+}
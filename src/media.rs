@@ -0,0 +1,198 @@
+use anyhow::{bail, Context, Result};
+use diesel::{
+    deserialize::{self, FromSql},
+    pg::Pg,
+    serialize::{self, Output, ToSql},
+    sql_types::Varchar,
+};
+use image::{io::Reader, DynamicImage};
+use serde::Serialize;
+use std::io::{Cursor, Write};
+use url::Url;
+
+/// Images with more pixels are not decoded. Compressed formats can describe
+/// a huge image in a few bytes, which would not fit into memory once decoded.
+pub const MAX_PIXELS: u64 = 50_000_000;
+
+/// How an image is displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Varchar"]
+pub enum MediaKind {
+    /// Still or animated picture shown with `<img>`
+    Image,
+    /// Video shown with `<video>`
+    Video,
+}
+
+impl MediaKind {
+    /// Get the kind of a file by its extension or content type essence
+    pub fn detect(extension: Option<&str>, content_type: Option<&str>) -> Self {
+        let is_video = content_type
+            .map_or(false, |content_type| content_type.starts_with("video/"))
+            || matches!(extension, Some("mp4") | Some("webm") | Some("gifv"));
+
+        if is_video {
+            MediaKind::Video
+        } else {
+            MediaKind::Image
+        }
+    }
+
+    /// Get the kind of the remote file at `url` by its content type essence,
+    /// falling back to the extension of the url
+    pub fn of_url(url: &Url, content_type: Option<&str>) -> Self {
+        let extension = url
+            .path()
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rfind('.').map(|pos| &name[pos + 1..]))
+            .map(str::to_lowercase);
+
+        MediaKind::detect(extension.as_deref(), content_type)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            MediaKind::Image => "image",
+            MediaKind::Video => "video",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for MediaKind {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<Varchar, Pg>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Varchar, Pg> for MediaKind {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match <String as FromSql<Varchar, Pg>>::from_sql(bytes)?.as_str() {
+            "image" => Ok(MediaKind::Image),
+            "video" => Ok(MediaKind::Video),
+            kind => Err(format!("Unknown media kind `{}`", kind).into()),
+        }
+    }
+}
+
+/// Imgur serves `.gifv` links as an html page embedding an mp4 video of the
+/// same name, so link the video directly
+pub fn resolve_gifv(mut url: Url) -> Url {
+    let is_imgur = url.domain().map_or(false, |domain| {
+        domain == "imgur.com" || domain.ends_with(".imgur.com")
+    });
+
+    if is_imgur && url.path().to_lowercase().ends_with(".gifv") {
+        let path = url.path();
+        let path = format!("{}.mp4", &path[..path.len() - ".gifv".len()]);
+        url.set_path(&path);
+    }
+
+    url
+}
+
+/// Read the width and height of the image in `data` from its header
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Fail if the header of the image in `data` declares more than
+/// [`MAX_PIXELS`] pixels. Images without readable dimensions are accepted.
+pub fn check_dimensions(data: &[u8]) -> Result<()> {
+    match dimensions(data) {
+        Some((width, height)) if u64::from(width) * u64::from(height) > MAX_PIXELS => bail!(
+            "Image is too large ({}x{} pixels, at most {} pixels are supported)",
+            width,
+            height,
+            MAX_PIXELS
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Decode the image in `data`, refusing images with more than
+/// [`MAX_PIXELS`] pixels before any pixel is decoded
+pub fn decode(data: &[u8]) -> Result<DynamicImage> {
+    if dimensions(data).is_none() {
+        bail!("Could not read image dimensions");
+    }
+    check_dimensions(data)?;
+
+    Reader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .decode()
+        .context("Could not decode image")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A GIF header declaring a 65535x65535 image without any pixel data
+    const BOMB: &[u8] = &[
+        b'G', b'I', b'F', b'8', b'9', b'a', 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x3b,
+    ];
+
+    #[test]
+    fn test_decode() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(40, 30)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+
+        assert_eq!(dimensions(&png), Some((40, 30)));
+        assert!(check_dimensions(&png).is_ok());
+        assert!(decode(&png).is_ok());
+
+        assert_eq!(dimensions(BOMB), Some((65535, 65535)));
+        assert!(check_dimensions(BOMB)
+            .unwrap_err()
+            .to_string()
+            .contains("too large"));
+        assert!(decode(BOMB).is_err());
+
+        assert!(check_dimensions(b"not an image").is_ok());
+        assert!(decode(b"not an image").is_err());
+    }
+
+    #[test]
+    fn test_resolve_gifv() {
+        let url = |s: &str| s.parse::<Url>().unwrap();
+
+        assert_eq!(
+            resolve_gifv(url("https://i.imgur.com/abc123.gifv")),
+            url("https://i.imgur.com/abc123.mp4")
+        );
+        assert_eq!(
+            resolve_gifv(url("https://example.com/abc123.gifv")),
+            url("https://example.com/abc123.gifv")
+        );
+        assert_eq!(
+            resolve_gifv(url("https://i.imgur.com/abc123.png")),
+            url("https://i.imgur.com/abc123.png")
+        );
+    }
+
+    #[test]
+    fn test_of_url() {
+        let url = |s: &str| s.parse::<Url>().unwrap();
+
+        assert_eq!(
+            MediaKind::of_url(&url("https://i.imgur.com/abc.MP4"), None),
+            MediaKind::Video
+        );
+        assert_eq!(
+            MediaKind::of_url(&url("https://i.imgur.com/abc"), Some("video/webm")),
+            MediaKind::Video
+        );
+        assert_eq!(
+            MediaKind::of_url(&url("https://i.imgur.com/abc.gif"), Some("image/gif")),
+            MediaKind::Image
+        );
+    }
+}
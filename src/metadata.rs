@@ -0,0 +1,266 @@
+use crate::{
+    fetch::{download, download_prefix, Probe},
+    media::{self, MediaKind},
+    models::{Image, ImageMetadata},
+    phash,
+    storage::{detect_format, Storage},
+};
+use anyhow::Result;
+use diesel::PgConnection;
+use image::{DynamicImage, ImageOutputFormat, RgbaImage};
+use log::{debug, info, warn};
+use rocket::http::ContentType;
+use std::{collections::HashMap, convert::TryFrom};
+use url::Url;
+
+/// Images are scaled down to fit this size before computing their placeholder
+const PLACEHOLDER_SOURCE_SIZE: u32 = 64;
+/// Size of the image a blurhash is decoded to
+const PLACEHOLDER_SIZE: u32 = 16;
+/// Number of images loaded from the database at once when computing
+/// missing metadata
+const BACKFILL_BATCH_SIZE: i64 = 100;
+/// Number of bytes downloaded of remote images, which is enough for the
+/// header of common formats
+const HEADER_SIZE: u64 = 64 * 1024;
+
+/// Read the metadata of an image from its content
+pub fn from_data(data: &[u8]) -> ImageMetadata {
+    let (width, height) = dimensions(data).unwrap_or_default();
+    let format = detect_format(data);
+    let image = media::decode(data)
+        .map_err(|err| debug!("Could not decode image: {}", err))
+        .ok();
+    let (blurhash, color) = match image.as_ref().map(placeholder) {
+        Some((blurhash, color)) => (Some(blurhash), Some(color)),
+        None => (None, None),
+    };
+    let placeholder = blurhash.as_deref().and_then(placeholder_url);
+
+    ImageMetadata {
+        width,
+        height,
+        content_type: format
+            .and_then(ContentType::from_extension)
+            .map(|content_type| content_type.to_string()),
+        size: i64::try_from(data.len()).ok(),
+        kind: format.map(|format| MediaKind::detect(Some(format), None)),
+        blurhash,
+        color,
+        phash: image.as_ref().map(phash::hash),
+        placeholder,
+    }
+}
+
+/// Get the metadata of the remote image at `url`.
+///
+/// Only the beginning of images is downloaded, to read their dimensions and
+/// type. Their placeholder and perceptual hash are computed later by
+/// [`backfill`]. For videos only the type and size from `probe` are known.
+pub fn from_remote(url: &Url, probe: &Probe) -> ImageMetadata {
+    let content_type = probe.essence();
+    let kind = MediaKind::of_url(url, content_type.as_deref());
+    let size = probe.size.and_then(|size| i64::try_from(size).ok());
+
+    if kind == MediaKind::Image {
+        match download_prefix(url, HEADER_SIZE) {
+            Ok(data) => {
+                let (width, height) = dimensions(&data).unwrap_or_default();
+                return ImageMetadata {
+                    width,
+                    height,
+                    content_type: detect_format(&data)
+                        .and_then(ContentType::from_extension)
+                        .map(|content_type| content_type.to_string())
+                        .or(content_type),
+                    size,
+                    kind: Some(kind),
+                    ..ImageMetadata::default()
+                };
+            }
+            Err(err) => debug!("Could not read {}: {}", url, err),
+        }
+    }
+
+    ImageMetadata {
+        content_type,
+        size,
+        kind: Some(kind),
+        ..ImageMetadata::default()
+    }
+}
+
+/// Read the dimensions from the header of an image
+fn dimensions(data: &[u8]) -> Option<(Option<i32>, Option<i32>)> {
+    let (width, height) = media::dimensions(data)?;
+
+    Some((i32::try_from(width).ok(), i32::try_from(height).ok()))
+}
+
+/// Compute the missing metadata of up to `limit` images and return how many
+/// were looked at.
+///
+/// Remote images of at most `max_size` bytes are downloaded to read them.
+/// Images in `unreadable` are skipped, and images which cannot be read are
+/// added to it.
+pub fn backfill(
+    conn: &PgConnection,
+    storage: &Storage,
+    max_size: u64,
+    unreadable: &mut Vec<i32>,
+    limit: i64,
+) -> Result<usize> {
+    let images = Image::with_missing_metadata(conn, unreadable, limit)?;
+
+    for image in &images {
+        match missing_metadata(image, storage, max_size) {
+            Ok(metadata) => image.set_metadata(conn, &metadata)?,
+            Err(err) => {
+                warn!("Could not read image {}: {:#}", image.token, err);
+                unreadable.push(image.id);
+            }
+        }
+    }
+
+    Ok(images.len())
+}
+
+/// Compute the missing metadata of all images
+pub fn backfill_all(conn: &PgConnection, storage: &Storage, max_size: u64) -> Result<()> {
+    let mut unreadable = Vec::new();
+    let mut checked = 0;
+    loop {
+        match backfill(
+            conn,
+            storage,
+            max_size,
+            &mut unreadable,
+            BACKFILL_BATCH_SIZE,
+        )? {
+            0 => break,
+            count => checked += count,
+        }
+    }
+
+    info!(
+        "Updated the metadata of {} images",
+        checked - unreadable.len()
+    );
+
+    Ok(())
+}
+
+/// Compute the metadata of a newly added remote image which
+/// [`from_remote`] leaves out
+pub fn complete(
+    conn: &PgConnection,
+    image: &Image,
+    storage: &Storage,
+    max_size: u64,
+) -> Result<()> {
+    image.set_metadata(conn, &missing_metadata(image, storage, max_size)?)
+}
+
+fn missing_metadata(image: &Image, storage: &Storage, max_size: u64) -> Result<ImageMetadata> {
+    // only the placeholder is missing, e.g. of images hashed before it was stored
+    if let (Some(blurhash), Some(_)) = (&image.blurhash, image.phash) {
+        return Ok(ImageMetadata {
+            placeholder: placeholder_url(blurhash),
+            ..ImageMetadata::default()
+        });
+    }
+
+    let data = match &image.file {
+        Some(file) => storage.read(file)?,
+        None => download(&image.url.parse()?, max_size)?,
+    };
+
+    Ok(from_data(&data))
+}
+
+/// Compute the blurhash and dominant color of an image
+fn placeholder(image: &DynamicImage) -> (String, String) {
+    let image = image
+        .thumbnail(PLACEHOLDER_SOURCE_SIZE, PLACEHOLDER_SOURCE_SIZE)
+        .to_rgba8();
+    let (width, height) = image.dimensions();
+
+    let (components_x, components_y) = if width >= height { (4, 3) } else { (3, 4) };
+    let blurhash = blurhash::encode(components_x, components_y, width, height, image.as_raw());
+
+    (blurhash, dominant_color(&image))
+}
+
+/// Get the most common color of `image`, ignoring small differences
+fn dominant_color(image: &RgbaImage) -> String {
+    let mut buckets: HashMap<[u8; 3], (u32, [u32; 3])> = HashMap::new();
+
+    for pixel in image.pixels().filter(|pixel| pixel[3] > 0) {
+        let bucket = buckets
+            .entry([pixel[0] >> 5, pixel[1] >> 5, pixel[2] >> 5])
+            .or_default();
+        bucket.0 += 1;
+        for (sum, &value) in bucket.1.iter_mut().zip(pixel.0.iter()) {
+            *sum += u32::from(value);
+        }
+    }
+
+    // the average of the largest bucket
+    let (count, sums) = buckets
+        .values()
+        .max_by_key(|(count, _)| *count)
+        .copied()
+        .unwrap_or((1, [0; 3]));
+
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        sums[0] / count,
+        sums[1] / count,
+        sums[2] / count
+    )
+}
+
+/// Decode `blurhash` into a small PNG data url
+fn placeholder_url(blurhash: &str) -> Option<String> {
+    let pixels = blurhash::decode(blurhash, PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, 1.0);
+    let image = RgbaImage::from_raw(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, pixels)?;
+
+    let mut png = Vec::new();
+    DynamicImage::ImageRgba8(image)
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .ok()?;
+
+    Some(format!("data:image/png;base64,{}", base64::encode(&png)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat};
+
+    #[test]
+    fn test_from_data() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(30, 20)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+
+        let metadata = from_data(&png);
+        assert_eq!(metadata.width, Some(30));
+        assert_eq!(metadata.height, Some(20));
+        assert_eq!(metadata.content_type.as_deref(), Some("image/png"));
+        assert_eq!(metadata.size, Some(png.len() as i64));
+        assert_eq!(metadata.kind, Some(MediaKind::Image));
+
+        assert_eq!(metadata.color.as_deref(), Some("#000000"));
+        assert!(metadata.blurhash.is_some());
+        assert!(metadata
+            .placeholder
+            .unwrap()
+            .starts_with("data:image/png;base64,"));
+
+        // dimensions can be read from a truncated file
+        let metadata = from_data(&png[..png.len() - 12]);
+        assert_eq!(metadata.width, Some(30));
+    }
+}
@@ -0,0 +1,47 @@
+//! Local http server answering requests with canned responses in tests
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    thread,
+};
+use url::Url;
+
+/// Answer one request per response on a local port and return the base url
+/// of the server
+pub fn serve<S>(responses: Vec<S>) -> Url
+where
+    S: AsRef<[u8]> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(response.as_ref()).unwrap();
+        }
+    });
+
+    url.parse().unwrap()
+}
+
+/// A `200 OK` response with the JSON `body` and the extra `headers`
+pub fn json(body: &str, headers: &[(&str, &str)]) -> String {
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{}",
+        body.len(),
+        headers,
+        body
+    )
+}
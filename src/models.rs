@@ -1,10 +1,27 @@
-use super::schema::{albums, images};
+use super::schema::{albums, blobs, images, import_jobs};
+use crate::{
+    jobs::JobStatus,
+    media::MediaKind,
+    phash::MAX_DISTANCE,
+    storage::{file_url, Storage},
+};
 use anyhow::{Context, Result};
 use diesel::{
-    insert_into, pg::Pg, update, BelongingToDsl, ExpressionMethods, PgConnection, QueryDsl,
+    delete,
+    dsl::{exists, not, sql},
+    insert_into,
+    pg::Pg,
+    sql_query,
+    sql_types::{Array, BigInt, Integer},
+    update, BelongingToDsl, BoolExpressionMethods, Connection, ExpressionMethods,
+    NullableExpressionMethods, OptionalExtension, PgConnection, PgSortExpressionMethods, QueryDsl,
     RunQueryDsl,
 };
 use rand::seq::SliceRandom;
+use std::time::SystemTime;
+
+/// Maximum number of characters in an album title
+pub const MAX_TITLE_LENGTH: usize = 64;
 
 /// generate a token, deletion-token pair
 /// The first token is 8 chars long and the second 16
@@ -33,16 +50,43 @@ pub struct Album {
     pub deletion_token: String,
 
     pub title: Option<String>,
+
+    /// Whether remote images are mirrored into the local storage
+    pub archive: bool,
+
+    /// Link of the album this album was imported from and is synced with
+    pub source: Option<String>,
 }
 
 impl Album {
-    pub fn new(conn: &PgConnection, title: Option<&str>) -> Result<Album> {
+    pub fn new(conn: &PgConnection, title: Option<&str>, archive: bool) -> Result<Album> {
+        Album::insert(conn, title, archive, None)
+    }
+
+    /// Create an album which is imported from the album at `source`
+    pub fn new_imported(
+        conn: &PgConnection,
+        title: Option<&str>,
+        archive: bool,
+        source: &str,
+    ) -> Result<Album> {
+        Album::insert(conn, title, archive, Some(source))
+    }
+
+    fn insert(
+        conn: &PgConnection,
+        title: Option<&str>,
+        archive: bool,
+        source: Option<&str>,
+    ) -> Result<Album> {
         let (token, deletion_token) = generate_token_pair();
 
         let new_album = NewAlbum {
             token: token.as_str(),
             deletion_token: deletion_token.as_str(),
             title,
+            archive,
+            source,
         };
 
         insert_into(albums::table)
@@ -51,6 +95,10 @@ impl Album {
             .context("Could not insert new album")
     }
 
+    pub fn by_id(id: i32) -> albums::BoxedQuery<'static, Pg> {
+        albums::table.find(id).into_boxed()
+    }
+
     pub fn by_token<'a>(token: &'a str) -> albums::BoxedQuery<'a, Pg> {
         albums::table.filter(albums::token.eq(token)).into_boxed()
     }
@@ -61,8 +109,85 @@ impl Album {
             .into_boxed()
     }
 
-    pub fn add_image(&self, conn: &PgConnection, url: &str, index: i32) -> Result<Image> {
-        Image::new(conn, self.id, url, index)
+    /// Set the title, shortened to [`MAX_TITLE_LENGTH`] characters
+    pub fn set_title(&self, conn: &PgConnection, title: &str) -> Result<()> {
+        let title: String = title.trim().chars().take(MAX_TITLE_LENGTH).collect();
+
+        update(albums::table.find(self.id))
+            .set(albums::title.eq(title))
+            .execute(conn)
+            .context("Could not set album title")?;
+        Ok(())
+    }
+
+    /// Add the remote image at `url`.
+    ///
+    /// If the album is archived the image is downloaded into `storage` and the
+    /// local copy is served instead, keeping `url` as its source.
+    pub fn add_image(
+        &self,
+        conn: &PgConnection,
+        storage: &Storage,
+        url: &str,
+        index: i32,
+    ) -> Result<Image> {
+        self.add_remote(conn, storage, url, None, index)
+    }
+
+    /// Add the remote image at `url` which is the image `upstream` of the
+    /// album this album is imported from
+    pub fn add_upstream_image(
+        &self,
+        conn: &PgConnection,
+        storage: &Storage,
+        url: &str,
+        upstream: &str,
+        index: i32,
+    ) -> Result<Image> {
+        self.add_remote(conn, storage, url, Some(upstream), index)
+    }
+
+    fn add_remote(
+        &self,
+        conn: &PgConnection,
+        storage: &Storage,
+        url: &str,
+        upstream: Option<&str>,
+        index: i32,
+    ) -> Result<Image> {
+        if !self.archive {
+            return Image::new(conn, self.id, url, None, None, upstream, index);
+        }
+
+        let file = storage.mirror(url)?;
+        self.insert_file(conn, &file, Some(url), upstream, index)
+    }
+
+    /// Add an image stored in the local storage
+    pub fn add_file(&self, conn: &PgConnection, file: &str, index: i32) -> Result<Image> {
+        self.insert_file(conn, file, None, None, index)
+    }
+
+    fn insert_file(
+        &self,
+        conn: &PgConnection,
+        file: &str,
+        source: Option<&str>,
+        upstream: Option<&str>,
+        index: i32,
+    ) -> Result<Image> {
+        conn.transaction(|| {
+            Blob::acquire(conn, file)?;
+            Image::new(
+                conn,
+                self.id,
+                &file_url(file),
+                Some(file),
+                source,
+                upstream,
+                index,
+            )
+        })
     }
 
     pub fn select_images<'a>(&'a self) -> images::BoxedQuery<'a, Pg> {
@@ -84,6 +209,70 @@ impl Album {
             .context("Could not get images belonging to album")
     }
 
+    /// Get at most `limit` images starting at position `start`.
+    ///
+    /// This seeks on `images.index` instead of using an offset so pages deep into
+    /// large albums are as cheap as the first one.
+    pub fn get_images_from(
+        &self,
+        conn: &PgConnection,
+        start: i32,
+        limit: i64,
+    ) -> Result<Vec<Image>> {
+        self.select_images()
+            .filter(images::index.ge(start))
+            .order_by(images::index)
+            .limit(limit)
+            .get_results(conn)
+            .context("Could not get images belonging to album")
+    }
+
+    pub fn get_image_at(&self, conn: &PgConnection, index: i32) -> Result<Option<Image>> {
+        self.select_images()
+            .filter(images::index.eq(index))
+            .first(conn)
+            .optional()
+            .context("Could not get image belonging to album")
+    }
+
+    /// Get the index and perceptual hash of all hashed images
+    pub fn image_hashes(&self, conn: &PgConnection) -> Result<Vec<(i32, i64)>> {
+        let hashes: Vec<(i32, Option<i64>)> = self
+            .select_images()
+            .filter(images::phash.is_not_null())
+            .select((images::index, images::phash))
+            .load(conn)
+            .context("Could not get image hashes")?;
+
+        Ok(hashes
+            .into_iter()
+            .filter_map(|(index, phash)| Some((index, phash?)))
+            .collect())
+    }
+
+    /// Get all images with a perceptual hash in order
+    pub fn hashed_images(&self, conn: &PgConnection) -> Result<Vec<Image>> {
+        self.select_images()
+            .filter(images::phash.is_not_null())
+            .order(images::index.asc())
+            .load(conn)
+            .context("Could not get hashed images")
+    }
+
+    /// Get the remote images whose last link check failed
+    pub fn broken_images(&self, conn: &PgConnection) -> Result<Vec<Image>> {
+        self.select_images()
+            .filter(images::file.is_null())
+            .filter(
+                images::link_status
+                    .eq(LINK_UNREACHABLE)
+                    .or(images::link_status.ge(400)),
+            )
+            .order(images::index.asc())
+            .load(conn)
+            .context("Could not get broken images")
+    }
+
     pub fn increase_index(&self, conn: &PgConnection, start: i32) -> Result<()> {
         update(Image::belonging_to(self).filter(images::index.ge(start)))
             .set(images::index.eq(images::index + 1))
@@ -91,9 +280,121 @@ impl Album {
         Ok(())
     }
 
+    pub fn decrease_index(&self, conn: &PgConnection, start: i32) -> Result<()> {
+        update(Image::belonging_to(self).filter(images::index.ge(start)))
+            .set(images::index.eq(images::index - 1))
+            .execute(conn)?;
+        Ok(())
+    }
+
     pub fn image_count(&self, conn: &PgConnection) -> Result<usize> {
         Ok(self.select_images().count().get_result::<i64>(conn)? as usize)
     }
+
+    /// Get the upstream links of all images imported from the source album
+    pub fn upstream_links(&self, conn: &PgConnection) -> Result<Vec<String>> {
+        let links: Vec<Option<String>> = self
+            .select_images()
+            .filter(images::upstream.is_not_null())
+            .select(images::upstream)
+            .load(conn)
+            .context("Could not get upstream links")?;
+
+        Ok(links.into_iter().flatten().collect())
+    }
+
+    /// Link the images without an upstream link which are one of the images
+    /// at `links` to it, so they are not imported again.
+    ///
+    /// Albums imported before syncing was supported only know their source,
+    /// so their images are linked on the first sync. Images the owner added
+    /// by hand are only linked if the source album has them as well.
+    ///
+    /// Returns the number of linked images.
+    pub fn adopt_upstream(&self, conn: &PgConnection, links: &[String]) -> Result<usize> {
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let from_source = update(
+                Image::belonging_to(self)
+                    .filter(images::upstream.is_null())
+                    .filter(images::source.eq_any(links)),
+            )
+            .set(images::upstream.eq(images::source))
+            .execute(conn)?;
+
+            let from_url = update(
+                Image::belonging_to(self)
+                    .filter(images::upstream.is_null())
+                    .filter(images::source.is_null())
+                    .filter(images::url.eq_any(links)),
+            )
+            .set(images::upstream.eq(images::url.nullable()))
+            .execute(conn)?;
+
+            Ok(from_source + from_url)
+        })
+        .context("Could not link images to the source album")
+    }
+
+    /// Flag the imported images which are not in `links` anymore as removed
+    /// upstream, and clear the flag of those which are back.
+    ///
+    /// Returns the number of newly flagged images.
+    pub fn flag_removed_upstream(&self, conn: &PgConnection, links: &[String]) -> Result<usize> {
+        conn.transaction(|| {
+            update(
+                Image::belonging_to(self)
+                    .filter(images::removed_upstream.eq(true))
+                    .filter(images::upstream.eq_any(links)),
+            )
+            .set(images::removed_upstream.eq(false))
+            .execute(conn)?;
+
+            update(
+                Image::belonging_to(self)
+                    .filter(images::removed_upstream.eq(false))
+                    .filter(images::upstream.ne_all(links)),
+            )
+            .set(images::removed_upstream.eq(true))
+            .execute(conn)
+        })
+        .context("Could not flag removed images")
+    }
+
+    /// Get the imported albums which were not synced since `synced_before`
+    /// and have no import in progress
+    pub fn due_for_sync(conn: &PgConnection, synced_before: SystemTime) -> Result<Vec<Album>> {
+        let recent_jobs = import_jobs::table
+            .filter(import_jobs::album_id.eq(albums::id))
+            .filter(
+                import_jobs::updated_at
+                    .ge(synced_before)
+                    .or(import_jobs::status.eq_any(vec![JobStatus::Queued, JobStatus::Running])),
+            );
+
+        albums::table
+            .filter(albums::source.is_not_null())
+            .filter(not(exists(recent_jobs)))
+            .load(conn)
+            .context("Could not get albums to sync")
+    }
+
+    /// Get the most recent import into this album, if it was imported
+    pub fn latest_import_job(&self, conn: &PgConnection) -> Result<Option<ImportJob>> {
+        ImportJob::belonging_to(self)
+            .order(import_jobs::id.desc())
+            .first(conn)
+            .optional()
+            .context("Could not get import job")
+    }
+
+    /// Sum of the sizes of all images with a known size in bytes
+    pub fn total_size(&self, conn: &PgConnection) -> Result<i64> {
+        // `SUM` of a bigint is a numeric, which is cast back to stay an `i64`
+        self.select_images()
+            .select(sql::<BigInt>("COALESCE(SUM(size), 0)::BIGINT"))
+            .get_result(conn)
+            .context("Could not get total image size")
+    }
 }
 
 #[derive(Debug, Insertable)]
@@ -103,6 +404,10 @@ pub struct NewAlbum<'a> {
     pub deletion_token: &'a str,
 
     pub title: Option<&'a str>,
+
+    pub archive: bool,
+
+    pub source: Option<&'a str>,
 }
 
 #[derive(Debug, Queryable, Identifiable, Associations)]
@@ -116,10 +421,58 @@ pub struct Image {
 
     pub url: String,
     pub index: i32,
+
+    /// Name of the file in the local storage, if the image was uploaded
+    pub file: Option<String>,
+
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub content_type: Option<String>,
+    /// Size in bytes
+    pub size: Option<i64>,
+
+    /// Original url of an image mirrored into the local storage
+    pub source: Option<String>,
+
+    /// HTTP status of the last link check, or [`LINK_UNREACHABLE`]
+    pub link_status: Option<i32>,
+    pub link_checked_at: Option<SystemTime>,
+
+    pub kind: MediaKind,
+
+    /// Blurred preview shown while the image loads
+    pub blurhash: Option<String>,
+    /// Dominant color as `#rrggbb`
+    pub color: Option<String>,
+
+    /// Perceptual hash used to find duplicates
+    pub phash: Option<i64>,
+
+    /// Link of this image in the source album, if it was imported
+    pub upstream: Option<String>,
+    /// Whether the image was removed from the source album
+    pub removed_upstream: bool,
+
+    /// Text shown below the image
+    pub caption: Option<String>,
+
+    /// The blurhash rendered as a PNG data url
+    pub placeholder: Option<String>,
 }
 
+/// Link status of a remote image whose server could not be reached
+pub const LINK_UNREACHABLE: i32 = 0;
+
 impl Image {
-    pub fn new(conn: &PgConnection, album_id: i32, url: &str, index: i32) -> Result<Image> {
+    pub fn new(
+        conn: &PgConnection,
+        album_id: i32,
+        url: &str,
+        file: Option<&str>,
+        source: Option<&str>,
+        upstream: Option<&str>,
+        index: i32,
+    ) -> Result<Image> {
         let (token, deletion_token) = generate_token_pair();
 
         insert_into(images::table)
@@ -129,6 +482,9 @@ impl Image {
                 deletion_token: deletion_token.as_str(),
                 index,
                 url,
+                file,
+                source,
+                upstream,
             })
             .get_result(conn)
             .context("Could not insert new image")
@@ -143,6 +499,154 @@ impl Image {
             .filter(images::deletion_token.eq(token))
             .into_boxed()
     }
+
+    pub fn set_metadata(&self, conn: &PgConnection, metadata: &ImageMetadata) -> Result<()> {
+        // diesel refuses to run an update without changes
+        if *metadata == ImageMetadata::default() {
+            return Ok(());
+        }
+
+        update(images::table.find(self.id))
+            .set(metadata)
+            .execute(conn)
+            .context("Could not update image metadata")?;
+        Ok(())
+    }
+
+    pub fn set_caption(&self, conn: &PgConnection, caption: Option<&str>) -> Result<()> {
+        update(images::table.find(self.id))
+            .set(images::caption.eq(caption))
+            .execute(conn)
+            .context("Could not update image caption")?;
+        Ok(())
+    }
+
+    /// Returns true if the last link check of a remote image failed
+    pub fn is_broken(&self) -> bool {
+        self.file.is_none()
+            && match self.link_status {
+                Some(status) => status == LINK_UNREACHABLE || status >= 400,
+                None => false,
+            }
+    }
+
+    /// Get up to `limit` remote images which were not checked since `checked_before`,
+    /// least recently checked first
+    pub fn due_for_link_check(
+        conn: &PgConnection,
+        checked_before: SystemTime,
+        limit: i64,
+    ) -> Result<Vec<Image>> {
+        images::table
+            .filter(images::file.is_null())
+            .filter(
+                images::link_checked_at
+                    .is_null()
+                    .or(images::link_checked_at.lt(checked_before)),
+            )
+            .order(images::link_checked_at.asc().nulls_first())
+            .limit(limit)
+            .load(conn)
+            .context("Could not get images to check")
+    }
+
+    pub fn set_link_status(
+        &self,
+        conn: &PgConnection,
+        status: i32,
+        checked_at: SystemTime,
+    ) -> Result<()> {
+        update(images::table.find(self.id))
+            .set((
+                images::link_status.eq(status),
+                images::link_checked_at.eq(checked_at),
+            ))
+            .execute(conn)
+            .context("Could not update link status")?;
+        Ok(())
+    }
+
+    /// Record a link check which could not tell whether the link works,
+    /// keeping the last known status
+    pub fn set_link_checked_at(&self, conn: &PgConnection, checked_at: SystemTime) -> Result<()> {
+        update(images::table.find(self.id))
+            .set(images::link_checked_at.eq(checked_at))
+            .execute(conn)
+            .context("Could not update link check time")?;
+        Ok(())
+    }
+
+    /// Get up to `limit` images which have no placeholder or perceptual hash
+    /// yet, except for the images in `skip`
+    pub fn with_missing_metadata(
+        conn: &PgConnection,
+        skip: &[i32],
+        limit: i64,
+    ) -> Result<Vec<Image>> {
+        images::table
+            .filter(
+                images::blurhash
+                    .is_null()
+                    .or(images::phash.is_null())
+                    .or(images::placeholder.is_null()),
+            )
+            .filter(images::kind.eq(MediaKind::Image))
+            .filter(images::id.ne_all(skip))
+            .order(images::id)
+            .limit(limit)
+            .load(conn)
+            .context("Could not get images with missing metadata")
+    }
+
+    /// Count the `hashes` which are similar to the hash of an image outside
+    /// of the album `album_id`
+    pub fn count_similar(conn: &PgConnection, album_id: i32, hashes: &[i64]) -> Result<usize> {
+        #[derive(QueryableByName)]
+        struct Count {
+            #[sql_type = "BigInt"]
+            count: i64,
+        }
+
+        // the distance is the number of ones in the XOR of the hashes
+        let Count { count } = sql_query(
+            "SELECT COUNT(*) AS count FROM unnest($1) AS new (phash) WHERE EXISTS ( \
+                SELECT 1 FROM images \
+                WHERE images.album_id <> $2 AND images.phash IS NOT NULL \
+                    AND length(replace((images.phash # new.phash)::BIT(64)::TEXT, '0', '')) <= $3)",
+        )
+        .bind::<Array<BigInt>, _>(hashes)
+        .bind::<Integer, _>(album_id)
+        .bind::<Integer, _>(MAX_DISTANCE as i32)
+        .get_result(conn)
+        .context("Could not look for similar images")?;
+
+        Ok(count as usize)
+    }
+
+    pub fn all_tokens(conn: &PgConnection) -> Result<Vec<String>> {
+        images::table
+            .select(images::token)
+            .get_results(conn)
+            .context("Could not get image tokens")
+    }
+
+    /// Delete this image.
+    ///
+    /// A file which is not referenced anymore is left for the garbage
+    /// collection, as another upload of the same content might reference it
+    /// again in the meantime.
+    pub fn delete(&self, conn: &PgConnection) -> Result<()> {
+        conn.transaction(|| {
+            delete(images::table.find(self.id))
+                .execute(conn)
+                .context("Could not delete image")?;
+
+            if let Some(file) = &self.file {
+                Blob::release(conn, file)?;
+            }
+            Ok(())
+        })
+    }
 }
 
 #[derive(Debug, Insertable)]
@@ -155,4 +659,223 @@ pub struct NewImage<'a> {
 
     pub url: &'a str,
     pub index: i32,
+
+    pub file: Option<&'a str>,
+    pub source: Option<&'a str>,
+    pub upstream: Option<&'a str>,
+}
+
+/// Properties of an image's content.
+/// Unknown properties are `None` and are not changed when updating an image.
+#[derive(Debug, Default, Clone, PartialEq, AsChangeset)]
+#[table_name = "images"]
+pub struct ImageMetadata {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub content_type: Option<String>,
+    pub size: Option<i64>,
+    pub kind: Option<MediaKind>,
+    pub blurhash: Option<String>,
+    /// Dominant color as `#rrggbb`
+    pub color: Option<String>,
+    pub phash: Option<i64>,
+    /// The blurhash rendered as a PNG data url
+    pub placeholder: Option<String>,
+}
+
+/// A file in the local storage shared by all images with the same content
+#[derive(Debug, Queryable, Identifiable)]
+#[primary_key(file)]
+pub struct Blob {
+    pub file: String,
+    pub ref_count: i32,
+}
+
+impl Blob {
+    /// Add a reference to the blob `file`, creating it if necessary
+    pub fn acquire(conn: &PgConnection, file: &str) -> Result<()> {
+        insert_into(blobs::table)
+            .values((blobs::file.eq(file), blobs::ref_count.eq(1)))
+            .on_conflict(blobs::file)
+            .do_update()
+            .set(blobs::ref_count.eq(blobs::ref_count + 1))
+            .execute(conn)
+            .context("Could not reference blob")?;
+        Ok(())
+    }
+
+    /// Remove a reference to the blob `file`.
+    ///
+    /// Returns true if this was the last reference and the blob was deleted.
+    pub fn release(conn: &PgConnection, file: &str) -> Result<bool> {
+        let ref_count: i32 = update(blobs::table.find(file))
+            .set(blobs::ref_count.eq(blobs::ref_count - 1))
+            .returning(blobs::ref_count)
+            .get_result(conn)
+            .context("Could not release blob")?;
+
+        if ref_count > 0 {
+            return Ok(false);
+        }
+
+        delete(blobs::table.find(file))
+            .execute(conn)
+            .context("Could not delete blob")?;
+        Ok(true)
+    }
+
+    /// Recount the references of all blobs and delete the unreferenced ones.
+    ///
+    /// Returns the files of the remaining blobs.
+    pub fn reconcile(conn: &PgConnection) -> Result<Vec<String>> {
+        conn.transaction(|| {
+            sql_query(
+                "UPDATE blobs SET ref_count = \
+                    (SELECT COUNT(*) FROM images WHERE images.file = blobs.file)",
+            )
+            .execute(conn)
+            .context("Could not recount blob references")?;
+
+            delete(blobs::table.filter(blobs::ref_count.le(0)))
+                .execute(conn)
+                .context("Could not delete unreferenced blobs")?;
+
+            blobs::table
+                .select(blobs::file)
+                .get_results(conn)
+                .context("Could not get blobs")
+        })
+    }
+}
+
+/// An import of the images of an album's source which are not in the album
+/// yet. It is run by the import worker in the background.
+#[derive(Debug, Queryable, Identifiable, Associations, AsChangeset)]
+#[belongs_to(Album, foreign_key = "album_id")]
+pub struct ImportJob {
+    pub id: i32,
+    pub album_id: i32,
+
+    /// Link of the album which is imported, or of the page the links of a
+    /// list were found on
+    pub url: Option<String>,
+    pub status: JobStatus,
+
+    /// Number of images of the source album which are not in the album yet,
+    /// once it is known
+    pub total: Option<i32>,
+    /// Number of images which were found to be valid
+    pub fetched: i32,
+    /// Number of images which were added to the album
+    pub inserted: i32,
+    /// Number of images which were left out because of an error
+    pub skipped: i32,
+
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+
+    /// Number of images which were newly removed from the source album
+    pub removed: i32,
+
+    /// Image links given by the user, which are added instead of the images
+    /// of a source album
+    pub links: Option<Vec<String>>,
+    /// Captions of the `links`, empty for images without one
+    pub captions: Vec<String>,
+    /// Position in the album of the first of the `links`
+    pub insert_at: i32,
+}
+
+/// A job adding a list of image links given by the user, e.g. pasted or
+/// picked from a web page, to an album
+#[derive(Debug, Insertable)]
+#[table_name = "import_jobs"]
+pub struct NewLinksJob<'a> {
+    pub album_id: i32,
+    /// Page the links were found on
+    pub url: Option<&'a str>,
+    pub links: &'a [String],
+    pub captions: &'a [String],
+    pub insert_at: i32,
+}
+
+impl NewLinksJob<'_> {
+    pub fn insert(&self, conn: &PgConnection) -> Result<ImportJob> {
+        insert_into(import_jobs::table)
+            .values(self)
+            .get_result(conn)
+            .context("Could not insert import job")
+    }
+}
+
+impl ImportJob {
+    /// Queue an import of the album at `url` into the album `album_id`
+    pub fn new(conn: &PgConnection, album_id: i32, url: &str) -> Result<ImportJob> {
+        insert_into(import_jobs::table)
+            .values((
+                import_jobs::album_id.eq(album_id),
+                import_jobs::url.eq(url),
+                import_jobs::status.eq(JobStatus::Queued),
+            ))
+            .get_result(conn)
+            .context("Could not insert import job")
+    }
+
+    /// Take the oldest queued job and mark it as running.
+    ///
+    /// Locked rows are skipped, so several workers never take the same job.
+    pub fn claim_next(conn: &PgConnection) -> Result<Option<ImportJob>> {
+        conn.transaction(|| {
+            let job: Option<ImportJob> = import_jobs::table
+                .filter(import_jobs::status.eq(JobStatus::Queued))
+                .order(import_jobs::id.asc())
+                .for_update()
+                .skip_locked()
+                .first(conn)
+                .optional()
+                .context("Could not get queued import job")?;
+
+            match job {
+                Some(mut job) => {
+                    job.status = JobStatus::Running;
+                    job.save(conn)?;
+                    Ok(Some(job))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Queue jobs again which are running but were not updated since
+    /// `updated_before`, because the worker running them stopped.
+    ///
+    /// Returns the number of jobs which are resumed.
+    pub fn requeue_stale(conn: &PgConnection, updated_before: SystemTime) -> Result<usize> {
+        let stale = import_jobs::table
+            .filter(import_jobs::status.eq(JobStatus::Running))
+            .filter(import_jobs::updated_at.lt(updated_before));
+
+        update(stale)
+            .set(import_jobs::status.eq(JobStatus::Queued))
+            .execute(conn)
+            .context("Could not requeue import jobs")
+    }
+
+    /// Number of images which were inserted or skipped so far
+    pub fn processed(&self) -> i32 {
+        self.inserted + self.skipped
+    }
+
+    /// Store the current status and progress of this job
+    pub fn save(&mut self, conn: &PgConnection) -> Result<()> {
+        self.updated_at = SystemTime::now();
+        update(&*self)
+            .set(&*self)
+            .execute(conn)
+            .context("Could not update import job")?;
+        Ok(())
+    }
 }
@@ -0,0 +1,98 @@
+use image::{imageops::FilterType, DynamicImage};
+
+/// Images whose hashes differ in at most this many bits are considered duplicates
+pub const MAX_DISTANCE: u32 = 6;
+
+/// Compute the perceptual difference hash of `image`.
+///
+/// The image is scaled down to 9x8 gray pixels and every bit of the hash
+/// records whether a pixel is brighter than its right neighbour, so the hash
+/// survives rescaling and recompression.
+pub fn hash(image: &DynamicImage) -> i64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    hash as i64
+}
+
+/// Returns true if the images with the hashes `a` and `b` look alike
+pub fn is_similar(a: i64, b: i64) -> bool {
+    (a ^ b).count_ones() <= MAX_DISTANCE
+}
+
+/// Group the items whose hashes are similar, leaving out items without
+/// duplicates
+pub fn clusters<T: Copy>(items: &[(T, i64)]) -> Vec<Vec<T>> {
+    // union find over the item positions
+    let mut parents: Vec<usize> = (0..items.len()).collect();
+
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    for i in 0..items.len() {
+        for j in i + 1..items.len() {
+            if is_similar(items[i].1, items[j].1) {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                parents[b] = a;
+            }
+        }
+    }
+
+    let mut clusters: Vec<(usize, Vec<T>)> = Vec::new();
+    for (i, &(item, _)) in items.iter().enumerate() {
+        let root = root(&mut parents, i);
+        match clusters.iter_mut().find(|(r, _)| *r == root) {
+            Some((_, cluster)) => cluster.push(item),
+            None => clusters.push((root, vec![item])),
+        }
+    }
+
+    clusters
+        .into_iter()
+        .map(|(_, cluster)| cluster)
+        .filter(|cluster| cluster.len() > 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    fn gradient(width: u32, height: u32, flip: bool) -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            let x = if flip { width - 1 - x } else { x };
+            let value = ((x * 255 / width + y * 64 / height) % 256) as u8;
+            Rgb([value, value, value])
+        }))
+    }
+
+    #[test]
+    fn test_hash() {
+        let original = hash(&gradient(200, 100, false));
+
+        assert!(is_similar(original, hash(&gradient(50, 25, false))));
+        assert!(!is_similar(original, hash(&gradient(200, 100, true))));
+    }
+
+    #[test]
+    fn test_clusters() {
+        let items = [(0, 0b0000), (1, 0b1111_1111_1111), (2, 0b0001), (3, 0b0011)];
+
+        assert_eq!(clusters(&items), vec![vec![0, 2, 3]]);
+    }
+}
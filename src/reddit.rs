@@ -0,0 +1,400 @@
+use crate::{
+    config::RedditConfig,
+    fetch::client,
+    import::{Importer, RemoteAlbum},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::header::USER_AGENT;
+use serde::{de::IgnoredAny, Deserialize};
+use std::collections::HashMap;
+use url::Url;
+
+/// The JSON of a post is a listing with the post followed by a listing with
+/// its comments, which are not needed
+type PostResponse = (Listing, IgnoredAny);
+
+#[derive(Debug, Deserialize)]
+struct Listing {
+    data: ListingData,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListingData {
+    children: Vec<Thing>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Thing {
+    data: Post,
+}
+
+#[derive(Debug, Deserialize)]
+struct Post {
+    title: String,
+    /// Link of the post, which is the image itself for image posts
+    url: Option<String>,
+    post_hint: Option<String>,
+    /// Order of the images of a gallery
+    gallery_data: Option<GalleryData>,
+    /// Images of a gallery by their media id
+    media_metadata: Option<HashMap<String, MediaMetadata>>,
+    secure_media: Option<Media>,
+    /// The original post of a crosspost
+    #[serde(default)]
+    crosspost_parent_list: Vec<Post>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GalleryData {
+    items: Vec<GalleryItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GalleryItem {
+    media_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaMetadata {
+    /// `valid` unless the image failed to process or was removed
+    status: String,
+    /// Content type, e.g. `image/jpg`
+    m: Option<String>,
+    /// The source in full size
+    s: Option<MediaSource>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaSource {
+    u: Option<String>,
+    gif: Option<String>,
+    mp4: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Media {
+    reddit_video: Option<RedditVideo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RedditVideo {
+    fallback_url: String,
+}
+
+impl Post {
+    /// Links of all images of the post in order
+    fn image_urls(&self) -> Vec<String> {
+        if let (Some(gallery), Some(metadata)) = (&self.gallery_data, &self.media_metadata) {
+            return gallery
+                .items
+                .iter()
+                .filter_map(|item| metadata.get(&item.media_id)?.link(&item.media_id))
+                .collect();
+        }
+
+        if let Some(parent) = self.crosspost_parent_list.first() {
+            return parent.image_urls();
+        }
+
+        if let Some(video) = self
+            .secure_media
+            .as_ref()
+            .and_then(|media| media.reddit_video.as_ref())
+        {
+            return vec![video.fallback_url.clone()];
+        }
+
+        match &self.url {
+            Some(url) if self.post_hint.as_deref() == Some("image") || is_image_host(url) => {
+                vec![url.clone()]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl MediaMetadata {
+    /// Link of the full size image, or `None` if it is not available
+    fn link(&self, media_id: &str) -> Option<String> {
+        if self.status != "valid" {
+            return None;
+        }
+
+        // the original file is served under its media id
+        let extension = match self.m.as_deref() {
+            Some("image/jpg") | Some("image/jpeg") => Some("jpg"),
+            Some("image/png") => Some("png"),
+            Some("image/gif") => Some("gif"),
+            _ => None,
+        };
+        if let Some(extension) = extension {
+            return Some(format!("https://i.redd.it/{}.{}", media_id, extension));
+        }
+
+        let source = self.s.as_ref()?;
+        source
+            .mp4
+            .as_ref()
+            .or_else(|| source.gif.as_ref())
+            .or_else(|| source.u.as_ref())
+            .cloned()
+    }
+}
+
+fn is_image_host(url: &str) -> bool {
+    Url::parse(url).map_or(false, |url| url.domain() == Some("i.redd.it"))
+}
+
+fn is_reddit(url: &Url) -> bool {
+    match url.domain().map(str::to_lowercase).as_deref() {
+        Some("redd.it") => true,
+        Some(domain) => domain == "reddit.com" || domain.ends_with(".reddit.com"),
+        None => false,
+    }
+}
+
+fn is_post_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+}
+
+/// Get the id of the post a Reddit link points to.
+///
+/// Links to posts in subreddits and user profiles, galleries, comments of a
+/// post and `redd.it` short links are accepted.
+pub fn post_id(url: &Url) -> Result<String, String> {
+    if !is_reddit(url) {
+        return Err(format!("Not a Reddit link: {}", url));
+    }
+
+    let segments: Vec<_> = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+
+    let id = if url.domain().map(str::to_lowercase).as_deref() == Some("redd.it") {
+        match segments.as_slice() {
+            [id] => Some(*id),
+            _ => None,
+        }
+    } else {
+        match segments.as_slice() {
+            ["r", _, "comments", id, ..]
+            | ["u", _, "comments", id, ..]
+            | ["user", _, "comments", id, ..]
+            | ["comments", id, ..]
+            | ["gallery", id] => Some(*id),
+            _ => None,
+        }
+    };
+
+    id.filter(|id| is_post_id(id))
+        .map(String::from)
+        .ok_or_else(|| format!("Not a link to a Reddit post: {}", url))
+}
+
+/// Imports the images of Reddit posts and galleries
+#[derive(Debug)]
+pub struct Reddit {
+    api_url: String,
+}
+
+impl Reddit {
+    pub fn new(config: &RedditConfig) -> Self {
+        Reddit {
+            api_url: config.api_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn post(&self, id: &str) -> Result<Post> {
+        // raw_json keeps reddit from escaping `&` in links as `&amp;`
+        let url = format!("{}/comments/{}.json?raw_json=1", self.api_url, id);
+
+        let resp = client()
+            .get(&url)
+            // requests with generic user agents are throttled
+            .header(
+                USER_AGENT,
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+            )
+            .send()
+            .context("Could not reach Reddit")?;
+
+        if !resp.status().is_success() {
+            bail!("Reddit said: {}", resp.status());
+        }
+
+        let (listing, _) = resp
+            .json::<PostResponse>()
+            .context("Invalid Reddit response")?;
+
+        listing
+            .data
+            .children
+            .into_iter()
+            .next()
+            .map(|thing| thing.data)
+            .ok_or_else(|| anyhow!("Post {} does not exist", id))
+    }
+}
+
+impl Importer for Reddit {
+    fn name(&self) -> &'static str {
+        "Reddit"
+    }
+
+    fn example(&self) -> &'static str {
+        "https://www.reddit.com/r/pics/comments/hs8k2q/"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        is_reddit(url)
+    }
+
+    fn validate(&self, url: &Url) -> Result<(), String> {
+        post_id(url).map(|_| ())
+    }
+
+    fn album(&self, url: &Url) -> Result<RemoteAlbum> {
+        let id = post_id(url).map_err(|err| anyhow!(err))?;
+        let post = self.post(&id)?;
+
+        let image_urls = post.image_urls();
+        if image_urls.is_empty() {
+            bail!("Post {} has no images", id);
+        }
+
+        Ok(RemoteAlbum {
+            title: Some(post.title),
+            image_urls,
+            ..RemoteAlbum::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    const GALLERY: &str = r#"[
+        {"kind": "Listing", "data": {"children": [{"kind": "t3", "data": {
+            "title": "My cats",
+            "url": "https://www.reddit.com/gallery/abc123",
+            "is_gallery": true,
+            "gallery_data": {"items": [
+                {"media_id": "second", "id": 2},
+                {"media_id": "first", "id": 1},
+                {"media_id": "failed", "id": 3},
+                {"media_id": "animated", "id": 4}
+            ]},
+            "media_metadata": {
+                "first": {"status": "valid", "e": "Image", "m": "image/jpg", "s": {"u": "https://preview.redd.it/first.jpg?width=100&s=x"}},
+                "second": {"status": "valid", "e": "Image", "m": "image/png", "s": {"u": "https://preview.redd.it/second.png?width=100&s=x"}},
+                "failed": {"status": "failed"},
+                "animated": {"status": "valid", "e": "AnimatedImage", "m": "video/mp4", "s": {"gif": "https://i.redd.it/animated.gif", "mp4": "https://preview.redd.it/animated.gif?format=mp4&s=x"}}
+            },
+            "secure_media": null
+        }}]}},
+        {"kind": "Listing", "data": {"children": [{"kind": "t1", "data": {"body": "nice"}}]}}
+    ]"#;
+
+    const IMAGE: &str = r#"[
+        {"kind": "Listing", "data": {"children": [{"kind": "t3", "data": {
+            "title": "A cat",
+            "url": "https://i.redd.it/cat.jpg",
+            "post_hint": "image",
+            "gallery_data": null,
+            "media_metadata": null,
+            "secure_media": null
+        }}]}},
+        {"kind": "Listing", "data": {"children": []}}
+    ]"#;
+
+    const TEXT: &str = r#"[
+        {"kind": "Listing", "data": {"children": [{"kind": "t3", "data": {
+            "title": "Question",
+            "url": "https://www.reddit.com/r/cats/comments/abc123/question/",
+            "post_hint": "self"
+        }}]}},
+        {"kind": "Listing", "data": {"children": []}}
+    ]"#;
+
+    fn reddit(response: &str) -> Reddit {
+        Reddit::new(&RedditConfig {
+            api_url: mock::serve(vec![mock::json(response, &[])]).to_string(),
+        })
+    }
+
+    fn album(response: &str) -> Result<RemoteAlbum> {
+        reddit(response).album(&"https://redd.it/abc123".parse().unwrap())
+    }
+
+    fn parse(url: &str) -> Result<String, String> {
+        post_id(&url.parse().unwrap())
+    }
+
+    #[test]
+    fn test_post_id() {
+        let id = || Ok("abc123".to_string());
+
+        assert_eq!(
+            parse("https://www.reddit.com/r/cats/comments/abc123/my_cats/"),
+            id()
+        );
+        assert_eq!(parse("https://old.reddit.com/r/cats/comments/abc123"), id());
+        assert_eq!(
+            parse("https://reddit.com/r/cats/comments/abc123/my_cats/def456/"),
+            id()
+        );
+        assert_eq!(
+            parse("https://www.reddit.com/user/someone/comments/abc123/x/"),
+            id()
+        );
+        assert_eq!(parse("https://www.reddit.com/comments/abc123"), id());
+        assert_eq!(parse("https://www.reddit.com/gallery/abc123"), id());
+        assert_eq!(parse("https://redd.it/abc123"), id());
+    }
+
+    #[test]
+    fn test_post_id_invalid() {
+        assert!(parse("https://www.reddit.com/r/cats").is_err());
+        assert!(parse("https://www.reddit.com/r/cats/comments/").is_err());
+        assert!(parse("https://www.reddit.com/gallery/ABC").is_err());
+        assert!(parse("https://redd.it/").is_err());
+        assert!(parse("https://i.redd.it/cat.jpg").is_err());
+        assert!(parse("https://notreddit.com/comments/abc123").is_err());
+    }
+
+    #[test]
+    fn test_gallery() {
+        let album = album(GALLERY).unwrap();
+
+        assert_eq!(album.title.as_deref(), Some("My cats"));
+        assert_eq!(
+            album.image_urls,
+            vec![
+                "https://i.redd.it/second.png",
+                "https://i.redd.it/first.jpg",
+                "https://preview.redd.it/animated.gif?format=mp4&s=x",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_image_post() {
+        let album = album(IMAGE).unwrap();
+
+        assert_eq!(album.title.as_deref(), Some("A cat"));
+        assert_eq!(album.image_urls, vec!["https://i.redd.it/cat.jpg"]);
+    }
+
+    #[test]
+    fn test_post_without_images() {
+        let err = album(TEXT).unwrap_err();
+
+        assert!(err.to_string().contains("has no images"));
+    }
+}
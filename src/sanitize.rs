@@ -0,0 +1,321 @@
+use crate::{media, storage::detect_format};
+use anyhow::{ensure, Context, Result};
+use image::{DynamicImage, ImageOutputFormat};
+use log::warn;
+use std::convert::TryInto;
+
+/// JPEG markers of segments which are kept: JFIF (APP0), ICC profiles (APP2)
+/// and the Adobe color transform (APP14). All other application segments and
+/// comments may contain metadata.
+const KEPT_JPEG_APP_MARKERS: [u8; 3] = [0xe0, 0xe2, 0xee];
+
+/// PNG chunks which may contain metadata
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// Remove EXIF, XMP and similar metadata from the image in `data`.
+///
+/// The EXIF orientation is applied to the pixels before it is removed, so the
+/// image is still displayed the right way up. Formats which rarely carry
+/// metadata (GIF and videos) are returned unchanged.
+pub fn strip_metadata(data: Vec<u8>) -> Result<Vec<u8>> {
+    match detect_format(&data) {
+        Some("jpg") => strip_jpeg(&data),
+        Some("png") => strip_png(&data),
+        Some("webp") => strip_webp(&data),
+        _ => Ok(data),
+    }
+}
+
+fn strip_jpeg(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut orientation = None;
+    out.extend_from_slice(&data[..2]);
+
+    let mut pos = 2;
+    loop {
+        ensure!(data.get(pos) == Some(&0xff), "Invalid JPEG marker");
+        // markers may be preceded by any number of fill bytes
+        while data.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        let marker = *data.get(pos + 1).context("Truncated JPEG")?;
+
+        // standalone markers without a length
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            out.extend_from_slice(&data[pos..pos + 2]);
+            pos += 2;
+            continue;
+        }
+        if marker == 0xd9 {
+            out.extend_from_slice(&data[pos..pos + 2]);
+            break;
+        }
+
+        let len = u16::from_be_bytes(
+            data.get(pos + 2..pos + 4)
+                .context("Truncated JPEG")?
+                .try_into()?,
+        ) as usize;
+        let end = pos + 2 + len;
+        ensure!(len >= 2 && end <= data.len(), "Truncated JPEG");
+        let segment = &data[pos + 4..end];
+
+        // the compressed image data follows the start of scan header
+        if marker == 0xda {
+            out.extend_from_slice(&data[pos..]);
+            break;
+        }
+
+        if marker == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            orientation = orientation.or_else(|| exif_orientation(&segment[6..]));
+        }
+
+        let is_metadata = marker == 0xfe
+            || ((0xe0..=0xef).contains(&marker) && !KEPT_JPEG_APP_MARKERS.contains(&marker));
+        if !is_metadata {
+            out.extend_from_slice(&data[pos..end]);
+        }
+
+        pos = end;
+    }
+
+    apply_orientation(out, orientation, ImageOutputFormat::Jpeg(90))
+}
+
+fn strip_png(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut orientation = None;
+    out.extend_from_slice(&data[..8]);
+
+    let mut pos = 8;
+    while pos < data.len() {
+        let len = u32::from_be_bytes(
+            data.get(pos..pos + 4)
+                .context("Truncated PNG")?
+                .try_into()?,
+        ) as usize;
+        let end = pos + 12 + len;
+        ensure!(end <= data.len(), "Truncated PNG");
+        let kind = &data[pos + 4..pos + 8];
+
+        if kind == b"eXIf" {
+            orientation = exif_orientation(&data[pos + 8..end - 4]);
+        }
+        if !PNG_METADATA_CHUNKS.iter().any(|chunk| chunk == &kind) {
+            out.extend_from_slice(&data[pos..end]);
+        }
+
+        pos = end;
+        if kind == b"IEND" {
+            break;
+        }
+    }
+
+    apply_orientation(out, orientation, ImageOutputFormat::Png)
+}
+
+fn strip_webp(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut orientation = None;
+    out.extend_from_slice(&data[..12]);
+
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let kind = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        // chunks are padded to an even length
+        let end = (pos + 8 + len + (len & 1)).min(data.len());
+        ensure!(pos + 8 + len <= data.len(), "Truncated WebP");
+        let chunk = &data[pos + 8..pos + 8 + len];
+
+        match kind {
+            b"EXIF" => {
+                let tiff = if chunk.starts_with(b"Exif\0\0") {
+                    &chunk[6..]
+                } else {
+                    chunk
+                };
+                orientation = exif_orientation(tiff);
+            }
+            b"XMP " => {}
+            b"VP8X" => {
+                let start = out.len();
+                out.extend_from_slice(&data[pos..end]);
+                // clear the flags announcing EXIF and XMP chunks
+                if let Some(flags) = out.get_mut(start + 8) {
+                    *flags &= !0x0c;
+                }
+            }
+            _ => out.extend_from_slice(&data[pos..end]),
+        }
+
+        pos = end;
+    }
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    // there is no WebP encoder, so rotated images are stored as PNG
+    apply_orientation(out, orientation, ImageOutputFormat::Png)
+}
+
+/// Read the orientation tag from the first IFD of the EXIF data in `tiff`
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let read_u16 = |pos: usize| -> Option<u16> {
+        let bytes = tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |pos: usize| -> Option<u32> {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let count = read_u16(ifd)? as usize;
+
+    (0..count)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(0x0112))
+        .and_then(|entry| read_u16(entry + 8))
+}
+
+/// Rotate and flip the image in `data` according to the EXIF `orientation`
+/// and encode it as `format`.
+///
+/// Images which are already upright are returned unchanged.
+fn apply_orientation(
+    data: Vec<u8>,
+    orientation: Option<u16>,
+    format: ImageOutputFormat,
+) -> Result<Vec<u8>> {
+    let orientation = match orientation {
+        Some(orientation @ 2..=8) => orientation,
+        _ => return Ok(data),
+    };
+
+    let image = match media::decode(&data) {
+        Ok(image) => image,
+        Err(err) => {
+            // the metadata is removed either way, the image is only displayed rotated
+            warn!("Could not decode image to apply its orientation: {}", err);
+            return Ok(data);
+        }
+    };
+
+    let image = orient(image, orientation);
+
+    let mut buf = Vec::new();
+    image
+        .write_to(&mut buf, format)
+        .context("Could not encode image")?;
+
+    Ok(buf)
+}
+
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, ImageFormat};
+
+    /// Little endian EXIF data containing only the orientation tag
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut exif = b"II\x2a\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(b"\0\0\0\0\0\0");
+        exif
+    }
+
+    fn jpeg_with_exif(orientation: u16) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        DynamicImage::new_rgb8(30, 20)
+            .write_to(&mut jpeg, ImageFormat::Jpeg)
+            .unwrap();
+
+        let mut segment = b"Exif\0\0".to_vec();
+        segment.extend(exif(orientation));
+        segment.extend_from_slice(b"GPS 52.5200 N 13.4050 E");
+
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&[0xff, 0xe1]);
+        data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        data.extend(segment);
+        data.extend_from_slice(&jpeg[2..]);
+        data
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_strip_jpeg() {
+        let stripped = strip_metadata(jpeg_with_exif(1)).unwrap();
+        assert!(!contains(&stripped, b"GPS"));
+        assert_eq!(
+            image::load_from_memory(&stripped).unwrap().dimensions(),
+            (30, 20)
+        );
+
+        let rotated = strip_metadata(jpeg_with_exif(6)).unwrap();
+        assert!(!contains(&rotated, b"GPS"));
+        assert_eq!(
+            image::load_from_memory(&rotated).unwrap().dimensions(),
+            (20, 30),
+            "orientation is applied to the pixels"
+        );
+    }
+
+    #[test]
+    fn test_strip_png() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(4, 2)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+
+        let text = b"tEXtComment\0GPS 52.5200 N 13.4050 E";
+        let mut chunk = ((text.len() - 4) as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(text);
+        chunk.extend_from_slice(&[0; 4]);
+
+        // insert the chunk after the IHDR chunk
+        let mut data = png[..33].to_vec();
+        data.extend(chunk);
+        data.extend_from_slice(&png[33..]);
+
+        let stripped = strip_metadata(data).unwrap();
+        assert_eq!(stripped, png);
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        assert_eq!(exif_orientation(&exif(6)), Some(6));
+        assert_eq!(exif_orientation(b"II\x2a\0\x08\0\0\0\0\0"), None);
+        assert_eq!(exif_orientation(b"garbage"), None);
+    }
+}
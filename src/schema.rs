@@ -4,6 +4,15 @@ table! {
         token -> Varchar,
         deletion_token -> Varchar,
         title -> Nullable<Varchar>,
+        archive -> Bool,
+        source -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    blobs (file) {
+        file -> Varchar,
+        ref_count -> Int4,
     }
 }
 
@@ -15,12 +24,52 @@ table! {
         deletion_token -> Varchar,
         url -> Varchar,
         index -> Int4,
+        file -> Nullable<Varchar>,
+        width -> Nullable<Int4>,
+        height -> Nullable<Int4>,
+        content_type -> Nullable<Varchar>,
+        size -> Nullable<Int8>,
+        source -> Nullable<Varchar>,
+        link_status -> Nullable<Int4>,
+        link_checked_at -> Nullable<Timestamp>,
+        kind -> Varchar,
+        blurhash -> Nullable<Varchar>,
+        color -> Nullable<Varchar>,
+        phash -> Nullable<Int8>,
+        upstream -> Nullable<Varchar>,
+        removed_upstream -> Bool,
+        caption -> Nullable<Varchar>,
+        placeholder -> Nullable<Text>,
+    }
+}
+
+table! {
+    import_jobs (id) {
+        id -> Int4,
+        album_id -> Int4,
+        url -> Nullable<Varchar>,
+        status -> Varchar,
+        total -> Nullable<Int4>,
+        fetched -> Int4,
+        inserted -> Int4,
+        skipped -> Int4,
+        errors -> Array<Text>,
+        warnings -> Array<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        removed -> Int4,
+        links -> Nullable<Array<Text>>,
+        captions -> Array<Text>,
+        insert_at -> Int4,
     }
 }
 
 joinable!(images -> albums (album_id));
+joinable!(import_jobs -> albums (album_id));
 
 allow_tables_to_appear_in_same_query!(
     albums,
+    blobs,
     images,
+    import_jobs,
 );
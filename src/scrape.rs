@@ -0,0 +1,519 @@
+//! Finds the images on a web page.
+//!
+//! Only the few tags which can reference images are of interest, so instead
+//! of building a document tree the page is scanned for tags and their
+//! attributes.
+
+use url::Url;
+
+/// Extensions of linked files which are offered as images
+const MEDIA_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "webp", "bmp", "mp4", "webm"];
+
+/// OpenGraph and Twitter card properties naming the preview image of a page
+const PREVIEW_PROPERTIES: [&str; 4] = [
+    "og:image",
+    "og:image:url",
+    "og:image:secure_url",
+    "twitter:image",
+];
+
+/// An image found on a page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub url: Url,
+    /// Width in pixels, if the page tells
+    pub width: Option<u32>,
+    /// Height in pixels, if the page tells
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+pub struct Page {
+    /// The OpenGraph title or else the `<title>` of the page
+    pub title: Option<String>,
+    /// Images in the order they appear on the page, without duplicates
+    pub candidates: Vec<Candidate>,
+}
+
+/// Find the images of the page `html` which was loaded from `url`.
+///
+/// Images are taken from `<img>` and `<source>` tags, where the largest
+/// `srcset` entry is preferred, OpenGraph and Twitter card previews and links
+/// to image and video files.
+pub fn scrape(html: &str, url: &Url) -> Page {
+    let mut base = url.clone();
+    let mut page = Page::default();
+    let mut og_title = None;
+    // urls as written on the page with their width and height
+    let mut found: Vec<(String, Option<u32>, Option<u32>)> = Vec::new();
+
+    for tag in Tags::new(html) {
+        let attribute = |name| tag.attribute(name);
+        let dimension = |name| attribute(name).and_then(|value| value.trim().parse().ok());
+
+        match tag.name.as_str() {
+            "base" => {
+                if let Some(href) = attribute("href").and_then(|href| url.join(href).ok()) {
+                    base = href;
+                }
+            }
+            "title" => {
+                if page.title.is_none() {
+                    page.title = tag.text.map(|text| text.trim().to_string());
+                }
+            }
+            "img" | "source" => {
+                let best = attribute("srcset")
+                    .or_else(|| attribute("data-srcset"))
+                    .and_then(largest_source);
+                let (src, width) = match best {
+                    Some((src, width)) => (Some(src), width.or_else(|| dimension("width"))),
+                    None => (
+                        attribute("data-src").or_else(|| attribute("src")),
+                        dimension("width"),
+                    ),
+                };
+
+                if let Some(src) = src {
+                    found.push((src.to_string(), width, dimension("height")));
+                }
+            }
+            "meta" => {
+                let property = attribute("property")
+                    .or_else(|| attribute("name"))
+                    .unwrap_or_default()
+                    .to_lowercase();
+                let content = attribute("content").unwrap_or_default();
+
+                // the size of a preview image follows its url
+                let last = found.last_mut();
+                match (property.as_str(), last) {
+                    ("og:title", _) => og_title = Some(content.trim().to_string()),
+                    ("og:image:width", Some(image)) => image.1 = content.trim().parse().ok(),
+                    ("og:image:height", Some(image)) => image.2 = content.trim().parse().ok(),
+                    (property, _) if PREVIEW_PROPERTIES.contains(&property) => {
+                        found.push((content.to_string(), None, None))
+                    }
+                    _ => {}
+                }
+            }
+            "a" => {
+                if let Some(href) = attribute("href").filter(|href| is_media_link(href)) {
+                    found.push((href.to_string(), None, None));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (src, width, height) in found {
+        let url = match base.join(src.trim()) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+            _ => continue,
+        };
+
+        match page.candidates.iter_mut().find(|other| other.url == url) {
+            // the same image may be linked and shown, only one of them tells its size
+            Some(other) => {
+                other.width = other.width.or(width);
+                other.height = other.height.or(height);
+            }
+            None => page.candidates.push(Candidate { url, width, height }),
+        }
+    }
+
+    page.title = og_title.or(page.title).filter(|title| !title.is_empty());
+
+    page
+}
+
+fn is_media_link(href: &str) -> bool {
+    let path = href
+        .split(|c| c == '?' || c == '#')
+        .next()
+        .unwrap_or_default();
+    let name = path.rsplit('/').next().unwrap_or_default();
+
+    name.rfind('.')
+        .map(|pos| name[pos + 1..].to_lowercase())
+        .map_or(false, |extension| {
+            MEDIA_EXTENSIONS.contains(&extension.as_str())
+        })
+}
+
+/// Get the largest source of a `srcset` and its width, if given
+fn largest_source(srcset: &str) -> Option<(&str, Option<u32>)> {
+    let mut best: Option<(&str, f64, Option<u32>)> = None;
+    let mut rest = srcset;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+
+        // urls may contain commas, so they end at whitespace only
+        let end = rest.find(char::is_whitespace).unwrap_or_else(|| rest.len());
+        let (src, after) = rest.split_at(end);
+        let (src, descriptor, after) = if src.ends_with(',') {
+            (src.trim_end_matches(','), "", after)
+        } else {
+            let end = after.find(',').unwrap_or_else(|| after.len());
+            (src, after[..end].trim(), &after[end..])
+        };
+        rest = after;
+
+        let (size, width) = match descriptor.chars().last() {
+            Some('w') => {
+                let width = descriptor[..descriptor.len() - 1].parse::<u32>().ok();
+                (width.map_or(0.0, f64::from), width)
+            }
+            Some('x') => (
+                descriptor[..descriptor.len() - 1].parse().unwrap_or(1.0),
+                None,
+            ),
+            _ => (1.0, None),
+        };
+
+        if best.map_or(true, |(_, best_size, _)| size > best_size) {
+            best = Some((src, size, width));
+        }
+    }
+
+    best.map(|(src, _, width)| (src, width))
+}
+
+/// A start tag with its attributes
+#[derive(Debug)]
+struct Tag {
+    /// Lowercase name
+    name: String,
+    /// Attributes with lowercase names and decoded values
+    attributes: Vec<(String, String)>,
+    /// Content of `<title>` tags
+    text: Option<String>,
+}
+
+impl Tag {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Iterator over the start tags of a page, skipping comments, scripts and
+/// styles
+struct Tags<'a> {
+    html: &'a str,
+    /// `html` in lowercase for searching end tags, with the same byte offsets
+    lower: String,
+    pos: usize,
+}
+
+impl<'a> Tags<'a> {
+    fn new(html: &'a str) -> Self {
+        Tags {
+            html,
+            lower: html.to_ascii_lowercase(),
+            pos: 0,
+        }
+    }
+
+    /// Find the next occurrence of the lowercase `pattern`, ignoring case
+    fn find_lowercase(&self, pattern: &str) -> Option<usize> {
+        self.lower[self.pos..]
+            .find(pattern)
+            .map(|found| self.pos + found)
+    }
+
+    /// Move behind the next occurrence of `pattern`, or to the end
+    fn skip_past(&mut self, pattern: &str) {
+        self.pos = self
+            .find_lowercase(pattern)
+            .map_or(self.html.len(), |found| found + pattern.len());
+    }
+
+    /// Read the text up to the end tag of `name`
+    fn text_until_end(&mut self, name: &str) -> String {
+        let start = self.pos;
+        let end = self
+            .find_lowercase(&format!("</{}", name))
+            .unwrap_or_else(|| self.html.len());
+        self.pos = end;
+        decode_entities(&self.html[start..end])
+    }
+
+    fn attributes(&mut self) -> Vec<(String, String)> {
+        let bytes = self.html.as_bytes();
+        let mut attributes = Vec::new();
+
+        loop {
+            while self.pos < bytes.len()
+                && (bytes[self.pos].is_ascii_whitespace() || bytes[self.pos] == b'/')
+            {
+                self.pos += 1;
+            }
+            // the page may end inside the tag
+            if self.pos >= bytes.len() {
+                return attributes;
+            }
+            if bytes[self.pos] == b'>' {
+                self.pos += 1;
+                return attributes;
+            }
+
+            let start = self.pos;
+            while self.pos < bytes.len()
+                && !bytes[self.pos].is_ascii_whitespace()
+                && !matches!(bytes[self.pos], b'=' | b'>' | b'/')
+            {
+                self.pos += 1;
+            }
+            let name = self.html[start..self.pos].to_ascii_lowercase();
+
+            while self.pos < bytes.len() && bytes[self.pos].is_ascii_whitespace() {
+                self.pos += 1;
+            }
+            if self.pos >= bytes.len() || bytes[self.pos] != b'=' {
+                attributes.push((name, String::new()));
+                continue;
+            }
+            self.pos += 1;
+            while self.pos < bytes.len() && bytes[self.pos].is_ascii_whitespace() {
+                self.pos += 1;
+            }
+
+            let value = match bytes.get(self.pos) {
+                Some(&quote) if quote == b'"' || quote == b'\'' => {
+                    let start = self.pos + 1;
+                    let end = self.html[start..]
+                        .find(quote as char)
+                        .map_or(self.html.len(), |end| start + end);
+                    self.pos = (end + 1).min(self.html.len());
+                    &self.html[start..end]
+                }
+                _ => {
+                    let start = self.pos;
+                    while self.pos < bytes.len()
+                        && !bytes[self.pos].is_ascii_whitespace()
+                        && bytes[self.pos] != b'>'
+                    {
+                        self.pos += 1;
+                    }
+                    &self.html[start..self.pos]
+                }
+            };
+
+            attributes.push((name, decode_entities(value)));
+        }
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        loop {
+            self.pos += self.html.get(self.pos..)?.find('<')?;
+            let rest = &self.html[self.pos + 1..];
+
+            if rest.starts_with("!--") {
+                self.pos += 4;
+                self.skip_past("-->");
+                continue;
+            }
+
+            let length = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or_else(|| rest.len());
+            if length == 0 {
+                // end tags, doctypes and stray `<`
+                self.pos += 1;
+                continue;
+            }
+
+            let name = rest[..length].to_ascii_lowercase();
+            self.pos += 1 + length;
+            let attributes = self.attributes();
+
+            let text = match name.as_str() {
+                "script" | "style" => {
+                    self.skip_past(&format!("</{}", name));
+                    continue;
+                }
+                "title" => Some(self.text_until_end("title")),
+                _ => None,
+            };
+
+            return Some(Tag {
+                name,
+                attributes,
+                text,
+            });
+        }
+    }
+}
+
+/// Decode the character references of an attribute value or text
+fn decode_entities(s: &str) -> String {
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<!doctype html>
+<html>
+<head>
+    <title>Blog &amp; stuff</title>
+    <meta property="og:title" content="My trip">
+    <meta property="og:image" content="https://cdn.example.com/hero.jpg">
+    <meta property="og:image:width" content="1200">
+    <script>var html = "<img src='script.png'>";</script>
+    <style>.x { background: url(style.png) }</style>
+</head>
+<body>
+    <!-- <img src="comment.png"> -->
+    <img src="/small.jpg" srcset="/small.jpg 320w, /large.jpg 1280w" alt="A view">
+    <IMG SRC='relative.png' width=16 height=16>
+    <img data-src="lazy.png" src="data:image/gif;base64,R0lGOD">
+    <picture><source srcset="pic.webp 1x, pic@2x.webp 2x"></picture>
+    <a href="https://cdn.example.com/hero.jpg">Full size</a>
+    <a href="/about">About</a>
+    <a href="files/clip.MP4?dl=1">Clip</a>
+</body>
+</html>"#;
+
+    fn urls(page: &Page) -> Vec<&str> {
+        page.candidates
+            .iter()
+            .map(|candidate| candidate.url.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_scrape() {
+        let url = "https://blog.example.com/posts/trip".parse().unwrap();
+        let page = scrape(PAGE, &url);
+
+        assert_eq!(page.title.as_deref(), Some("My trip"));
+        assert_eq!(
+            urls(&page),
+            vec![
+                "https://cdn.example.com/hero.jpg",
+                "https://blog.example.com/large.jpg",
+                "https://blog.example.com/posts/relative.png",
+                "https://blog.example.com/posts/lazy.png",
+                "https://blog.example.com/posts/pic@2x.webp",
+                "https://blog.example.com/posts/files/clip.MP4?dl=1",
+            ]
+        );
+        assert_eq!(page.candidates[0].width, Some(1200));
+        assert_eq!(page.candidates[1].width, Some(1280));
+        assert_eq!(page.candidates[2].height, Some(16));
+    }
+
+    #[test]
+    fn test_scrape_base() {
+        let url = "https://blog.example.com/posts/trip".parse().unwrap();
+        let page = scrape(
+            r#"<title> Trip </title><base href="https://cdn.example.com/"><img src="a.png">"#,
+            &url,
+        );
+
+        assert_eq!(page.title.as_deref(), Some("Trip"));
+        assert_eq!(urls(&page), vec!["https://cdn.example.com/a.png"]);
+    }
+
+    #[test]
+    fn test_scrape_truncated() {
+        let url = "https://blog.example.com/".parse().unwrap();
+
+        for html in &[
+            "<",
+            "<img",
+            "<img ",
+            "<img src",
+            "<img src=a",
+            "<img src=\"a",
+            "<!--",
+        ] {
+            scrape(html, &url);
+        }
+        assert_eq!(
+            urls(&scrape("<img src=a.png", &url)),
+            vec!["https://blog.example.com/a.png"]
+        );
+    }
+
+    #[test]
+    fn test_largest_source() {
+        assert_eq!(
+            largest_source("a.jpg 320w, b.jpg 1280w, c.jpg 640w"),
+            Some(("b.jpg", Some(1280)))
+        );
+        assert_eq!(largest_source("a.jpg, b.jpg 2x"), Some(("b.jpg", None)));
+        assert_eq!(
+            largest_source("https://cdn.com/w_100,h_100/a.jpg 100w"),
+            Some(("https://cdn.com/w_100,h_100/a.jpg", Some(100)))
+        );
+        assert_eq!(largest_source(" "), None);
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(decode_entities("a &amp; b"), "a & b");
+        assert_eq!(decode_entities("&#39;&#x41;&quot;"), "'A\"");
+        assert_eq!(decode_entities("?a=1&b=2"), "?a=1&b=2");
+        assert_eq!(decode_entities("&unknown; &"), "&unknown; &");
+    }
+}
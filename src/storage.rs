@@ -0,0 +1,328 @@
+use crate::{
+    config::StorageConfig,
+    fetch::download,
+    media,
+    models::{generate_token_pair, Blob},
+    sanitize::strip_metadata,
+};
+use anyhow::{ensure, Context, Result};
+use diesel::PgConnection;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use url::Url;
+
+/// Files younger than this are never garbage collected, as they might be in
+/// the process of being added to an album.
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Content addressed file storage for uploaded images.
+///
+/// Files are named after the SHA-256 hash of their content, so every distinct
+/// file is only stored once. The `blobs` table counts how many images use a file.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    directory: PathBuf,
+    max_download_size: u64,
+    keep_metadata: bool,
+}
+
+impl Storage {
+    pub fn new(config: &StorageConfig) -> Result<Self> {
+        fs::create_dir_all(&config.directory).with_context(|| {
+            format!(
+                "Could not create storage directory {}",
+                config.directory.display()
+            )
+        })?;
+
+        Ok(Storage {
+            directory: config.directory.clone(),
+            max_download_size: config.max_download_size,
+            keep_metadata: config.keep_metadata,
+        })
+    }
+
+    /// Store `data` and return the name of its file.
+    ///
+    /// Fails if `data` is not in one of the supported image or video formats.
+    pub fn store(&self, data: &[u8]) -> Result<String> {
+        let extension = detect_format(data).context("Unsupported file format")?;
+        let name = format!("{:x}.{}", Sha256::digest(data), extension);
+        let path = self.directory.join(&name);
+
+        // an existing file is written again, as the new modification time
+        // keeps the garbage collection from removing it before the caller
+        // references it, even if it was unused for a long time
+        write_file(&path, data).with_context(|| format!("Could not create file {}", name))?;
+
+        Ok(name)
+    }
+
+    /// Get the content an image or video added to an album is stored with.
+    ///
+    /// Images which would not fit into memory once decoded are refused, as
+    /// they are decoded later for metadata and thumbnails. Unless the
+    /// instance keeps metadata, EXIF and XMP data is removed.
+    pub fn prepare(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        media::check_dimensions(&data)?;
+
+        if self.keep_metadata {
+            Ok(data)
+        } else {
+            strip_metadata(data)
+        }
+    }
+
+    /// Download the remote file at `url` and store it like an upload
+    pub fn mirror(&self, url: &str) -> Result<String> {
+        let url: Url = url
+            .parse()
+            .with_context(|| format!("Invalid url {}", url))?;
+        let data = download(&url, self.max_download_size)?;
+        let data = self
+            .prepare(data)
+            .with_context(|| format!("Could not process {}", url))?;
+
+        self.store(&data)
+            .with_context(|| format!("Could not store {}", url))
+    }
+
+    /// Read the stored file `name`
+    pub fn read(&self, name: &str) -> Result<Vec<u8>> {
+        fs::read(self.path(name)?).with_context(|| format!("Could not read file {}", name))
+    }
+
+    /// Read the stored file `name` to add it to an album again.
+    ///
+    /// Like in [`Storage::store`], the file is written again so the garbage
+    /// collection keeps it until the caller references it.
+    pub fn reuse(&self, name: &str) -> Result<Vec<u8>> {
+        let data = self.read(name)?;
+        write_file(&self.path(name)?, &data)
+            .with_context(|| format!("Could not refresh file {}", name))?;
+
+        Ok(data)
+    }
+
+    /// Create an anonymous temporary file in the storage directory.
+    ///
+    /// The file is removed from the directory right away and deleted once it
+    /// is closed.
+    pub fn temp_file(&self) -> Result<File> {
+        let (token, _) = generate_token_pair();
+        let path = self.directory.join(format!(".{}.tmp", token));
+
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .context("Could not create temporary file")?;
+        fs::remove_file(&path).context("Could not unlink temporary file")?;
+
+        Ok(f)
+    }
+
+    /// Remove all files which are not used by any image
+    pub fn collect_garbage(&self, conn: &PgConnection) -> Result<()> {
+        let blobs: HashSet<String> = Blob::reconcile(conn)?.into_iter().collect();
+        let now = SystemTime::now();
+        let mut removed = 0;
+
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            if !metadata.is_file() || blobs.contains(&name) {
+                continue;
+            }
+
+            let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+            if age < GC_GRACE_PERIOD {
+                continue;
+            }
+
+            match fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(err) => warn!("Could not remove file {}: {}", name, err),
+            }
+        }
+
+        info!("Removed {} unused files", removed);
+
+        Ok(())
+    }
+
+    /// Get the path of the stored file `name`.
+    ///
+    /// Fails if `name` is not a plain file name.
+    pub fn path(&self, name: &str) -> Result<PathBuf> {
+        ensure!(is_valid_name(name), "Invalid file name");
+        Ok(self.directory.join(name))
+    }
+}
+
+/// Write `data` to `path`, replacing the file at once so readers never see
+/// partial content
+pub fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let (token, _) = generate_token_pair();
+    let tmp_path = path.with_file_name(format!(".{}.tmp", token));
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+    f.write_all(data)?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// The url a stored file is served at
+pub fn file_url(name: &str) -> String {
+    format!("/f/{}", name)
+}
+
+/// Get the name of the stored file `url` is the url of, see [`file_url`]
+pub fn file_name(url: &str) -> Option<&str> {
+    url.strip_prefix("/f/").filter(|name| is_valid_name(name))
+}
+
+/// Returns true if `name` can not escape the storage directory
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Guess the file extension by looking at the magic bytes of `data`
+pub fn detect_format(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some("png"),
+        [0xff, 0xd8, 0xff, ..] => Some("jpg"),
+        [b'G', b'I', b'F', b'8', b'7', b'a', ..] | [b'G', b'I', b'F', b'8', b'9', b'a', ..] => {
+            Some("gif")
+        }
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("webp"),
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => Some("webm"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("mp4"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("png"));
+        assert_eq!(detect_format(b"GIF89a\x01\0\x01\0"), Some("gif"));
+        assert_eq!(detect_format(b"RIFF\0\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(detect_format(b"<!doctype html>"), None);
+        assert_eq!(detect_format(b""), None);
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name(&file_url("aBc.png")), Some("aBc.png"));
+        assert_eq!(file_name("/f/../config.toml"), None);
+        assert_eq!(file_name("https://v.example.com/f/aBc.png"), None);
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("aBc123xY.png"));
+        assert!(!is_valid_name("../config.toml"));
+        assert!(!is_valid_name("a/b.png"));
+        assert!(!is_valid_name(".hidden"));
+        assert!(!is_valid_name(""));
+    }
+
+    /// Storage in a new temporary directory, which is removed by the caller
+    fn temp_storage(config: StorageConfig) -> Storage {
+        let (token, _) = generate_token_pair();
+        Storage::new(&StorageConfig {
+            directory: std::env::temp_dir().join(format!("v-storage-{}", token)),
+            ..config
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_store_refreshes_existing_file() {
+        let storage = temp_storage(StorageConfig::default());
+        let modified = |name: &str| {
+            fs::metadata(storage.path(name).unwrap())
+                .and_then(|metadata| metadata.modified())
+                .unwrap()
+        };
+
+        let data = b"GIF89a\x01\0\x01\0";
+        let name = storage.store(data).unwrap();
+        let first = modified(&name);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(storage.store(data).unwrap(), name);
+        assert!(modified(&name) > first, "storing refreshes the file");
+        assert_eq!(storage.read(&name).unwrap(), data);
+
+        let second = modified(&name);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(storage.reuse(&name).unwrap(), data);
+        assert!(modified(&name) > second, "reusing refreshes the file");
+
+        fs::remove_dir_all(&storage.directory).unwrap();
+    }
+
+    #[test]
+    fn test_mirror_strips_metadata() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 2)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        // a text chunk right after the header, its checksum is never read
+        let mut chunk = 19u32.to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"tEXtComment\0GPS 52.52 N\0\0\0\0");
+        png.splice(33..33, chunk);
+
+        let response = |png: &[u8]| {
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+                png.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(png);
+            response
+        };
+        let contains_gps = |data: &[u8]| data.windows(3).any(|window| window == b"GPS");
+        let base = mock::serve(vec![response(&png), response(&png)]);
+        let url = base.join("cat.png").unwrap();
+
+        let storage = temp_storage(StorageConfig::default());
+        let name = storage.mirror(url.as_str()).unwrap();
+        let data = storage.read(&name).unwrap();
+        assert!(!contains_gps(&data));
+        assert_eq!(detect_format(&data), Some("png"));
+        fs::remove_dir_all(&storage.directory).unwrap();
+
+        let storage = temp_storage(StorageConfig {
+            keep_metadata: true,
+            ..StorageConfig::default()
+        });
+        let name = storage.mirror(url.as_str()).unwrap();
+        assert!(contains_gps(&storage.read(&name).unwrap()));
+        fs::remove_dir_all(&storage.directory).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{config::ThumbnailConfig, media, storage::write_file};
+use anyhow::{Context, Result};
+use image::{imageops::FilterType, GenericImageView, ImageOutputFormat};
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Disk cache for downscaled versions of images
+#[derive(Debug)]
+pub struct Thumbnails {
+    directory: PathBuf,
+    widths: Vec<u32>,
+}
+
+impl Thumbnails {
+    pub fn new(config: &ThumbnailConfig, storage_directory: &Path) -> Result<Self> {
+        let directory = config
+            .directory
+            .clone()
+            .unwrap_or_else(|| storage_directory.join("thumbs"));
+
+        fs::create_dir_all(&directory).with_context(|| {
+            format!(
+                "Could not create thumbnail directory {}",
+                directory.display()
+            )
+        })?;
+
+        let mut widths = config.widths.clone();
+        widths.sort_unstable();
+        widths.dedup();
+
+        Ok(Thumbnails { directory, widths })
+    }
+
+    /// The configured thumbnail widths in ascending order
+    pub fn widths(&self) -> &[u32] {
+        &self.widths
+    }
+
+    pub fn is_allowed_width(&self, width: u32) -> bool {
+        self.widths.contains(&width)
+    }
+
+    /// Get the thumbnail of the image `token`.
+    ///
+    /// If it is not cached yet it is generated from the image data returned by `source`.
+    pub fn get<F>(&self, token: &str, width: u32, source: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        let path = self.directory.join(format!("{}-{}", token, width));
+
+        if let Ok(data) = fs::read(&path) {
+            return Ok(data);
+        }
+
+        let data = generate(&source()?, width)?;
+
+        if let Err(err) = write_file(&path, &data) {
+            warn!("Could not cache thumbnail {}: {}", path.display(), err);
+        }
+
+        Ok(data)
+    }
+
+    /// Remove the thumbnails of all images not in `tokens`
+    pub fn collect_garbage(&self, tokens: &HashSet<String>) -> Result<()> {
+        let mut removed = 0;
+
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            let token = match name.rfind('-') {
+                Some(pos) => &name[..pos],
+                None => continue,
+            };
+
+            if tokens.contains(token) {
+                continue;
+            }
+
+            match fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(err) => warn!("Could not remove thumbnail {}: {}", name, err),
+            }
+        }
+
+        info!("Removed {} unused thumbnails", removed);
+
+        Ok(())
+    }
+}
+
+/// Scale the image in `data` down to `width` pixels.
+///
+/// Images with transparency are encoded as PNG, everything else as JPEG.
+fn generate(data: &[u8], width: u32) -> Result<Vec<u8>> {
+    let image = media::decode(data)?;
+
+    let image = if image.width() > width {
+        image.resize(width, u32::MAX, FilterType::Triangle)
+    } else {
+        image
+    };
+
+    let format = if image.color().has_alpha() {
+        ImageOutputFormat::Png
+    } else {
+        ImageOutputFormat::Jpeg(85)
+    };
+
+    let mut buf = Vec::new();
+    image
+        .write_to(&mut buf, format)
+        .context("Could not encode thumbnail")?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat};
+
+    #[test]
+    fn test_generate() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(100, 50)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+
+        let thumbnail = image::load_from_memory(&generate(&png, 40).unwrap()).unwrap();
+        assert_eq!(thumbnail.dimensions(), (40, 20));
+
+        let thumbnail = image::load_from_memory(&generate(&png, 400).unwrap()).unwrap();
+        assert_eq!(
+            thumbnail.dimensions(),
+            (100, 50),
+            "small images are not scaled up"
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use crate::{
+    avif,
+    config::TransformConfig,
+    media,
+    storage::{write_file, Storage},
+};
+use anyhow::{anyhow, Context, Result};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageOutputFormat};
+use log::{info, warn};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Quality lossy formats are encoded with if none is requested
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Separates the name of the source file from the transform in the names of
+/// cached files. It can not occur in stored file names.
+const SEPARATOR: char = '@';
+
+/// Format a transformed image is encoded as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+
+    fn is_lossy(self) -> bool {
+        self != Format::Png
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(Format::Jpeg),
+            "png" => Ok(Format::Png),
+            "webp" => Ok(Format::Webp),
+            "avif" => Ok(Format::Avif),
+            _ => Err(format!("Unsupported format {}", s)),
+        }
+    }
+}
+
+/// How an image is fit into the requested size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale the image down until it fits, keeping its aspect ratio
+    Scale,
+    /// Scale and crop the image so it fills exactly the requested size
+    Crop,
+}
+
+impl Fit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Fit::Scale => "scale",
+            Fit::Crop => "crop",
+        }
+    }
+}
+
+impl Default for Fit {
+    fn default() -> Self {
+        Fit::Scale
+    }
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scale" => Ok(Fit::Scale),
+            "crop" => Ok(Fit::Crop),
+            _ => Err(format!("Unknown fit {}", s)),
+        }
+    }
+}
+
+/// Resizes, crops and re-encodes an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+    pub format: Format,
+    /// Quality from 1 to 100, only used by lossy formats
+    pub quality: Option<u8>,
+}
+
+impl Transform {
+    /// Scale images down to `width` pixels and encode them as `format`
+    pub fn scale(width: u32, format: Format) -> Self {
+        Transform {
+            width: Some(width),
+            height: None,
+            fit: Fit::Scale,
+            format,
+            quality: None,
+        }
+    }
+
+    /// Check that the parameters of the transform make sense together
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err("Width and height must be positive".to_string());
+        }
+        if self.fit == Fit::Crop && (self.width.is_none() || self.height.is_none()) {
+            return Err("Cropping requires a width and a height".to_string());
+        }
+        if let Some(quality) = self.quality {
+            if !(1..=100).contains(&quality) {
+                return Err("Quality must be between 1 and 100".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fill in the default quality and drop it for lossless formats, so
+    /// transforms with the same result compare equal
+    fn normalize(self) -> Self {
+        let quality = if self.format.is_lossy() {
+            Some(self.quality.unwrap_or(DEFAULT_QUALITY))
+        } else {
+            None
+        };
+
+        Transform { quality, ..self }
+    }
+
+    /// Unique name of the result of the normalized transform
+    fn key(&self) -> String {
+        format!(
+            "{}x{}-{}-q{}.{}",
+            self.width.unwrap_or(0),
+            self.height.unwrap_or(0),
+            self.fit.as_str(),
+            self.quality.unwrap_or(0),
+            self.format.extension()
+        )
+    }
+
+    /// Apply the transform to the image in `data`
+    pub fn apply(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let image = media::decode(data)?;
+        let image = self.resize(image);
+
+        encode(&image, self.format, self.quality.unwrap_or(DEFAULT_QUALITY))
+    }
+
+    fn resize(&self, image: DynamicImage) -> DynamicImage {
+        match (self.fit, self.width, self.height) {
+            (Fit::Crop, Some(width), Some(height)) => {
+                image.resize_to_fill(width, height, FilterType::Triangle)
+            }
+            (_, None, None) => image,
+            (_, width, height) => {
+                let width = width.unwrap_or(u32::MAX);
+                let height = height.unwrap_or(u32::MAX);
+
+                // small images are not scaled up
+                if image.width() > width || image.height() > height {
+                    image.resize(width, height, FilterType::Triangle)
+                } else {
+                    image
+                }
+            }
+        }
+    }
+}
+
+fn encode(image: &DynamicImage, format: Format, quality: u8) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    match format {
+        // JPEG has no alpha channel
+        Format::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut buf, ImageOutputFormat::Jpeg(quality)),
+        Format::Png => image.write_to(&mut buf, ImageOutputFormat::Png),
+        Format::Webp => {
+            let image = image.to_rgba8();
+            let data = webp::Encoder::from_rgba(&image, image.width(), image.height())
+                .encode(f32::from(quality));
+            buf.extend_from_slice(&data);
+            Ok(())
+        }
+        Format::Avif => {
+            return avif::encode(&image.to_rgba8(), quality).context("Could not encode image")
+        }
+    }
+    .context("Could not encode image")?;
+
+    Ok(buf)
+}
+
+/// Disk cache for transformed versions of stored images.
+///
+/// Only the configured presets may be requested, so the number of cached
+/// files per image is bounded.
+#[derive(Debug)]
+pub struct Transforms {
+    directory: PathBuf,
+    presets: Vec<Transform>,
+}
+
+impl Transforms {
+    pub fn new(config: &TransformConfig, storage_directory: &Path) -> Result<Self> {
+        let directory = config
+            .directory
+            .clone()
+            .unwrap_or_else(|| storage_directory.join("transforms"));
+
+        fs::create_dir_all(&directory).with_context(|| {
+            format!(
+                "Could not create transform directory {}",
+                directory.display()
+            )
+        })?;
+
+        let presets = config
+            .presets
+            .iter()
+            .map(|preset| {
+                preset
+                    .validate()
+                    .map(|()| preset.normalize())
+                    .map_err(|err| anyhow!("Invalid transform preset {:?}: {}", preset, err))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Transforms { directory, presets })
+    }
+
+    /// Returns true if `transform` matches one of the presets
+    pub fn is_allowed(&self, transform: &Transform) -> bool {
+        self.presets.contains(&transform.normalize())
+    }
+
+    /// Get the result of applying `transform` to the stored file `name`.
+    ///
+    /// If it is not cached yet it is generated from the image data returned by `source`.
+    pub fn get<F>(&self, name: &str, transform: &Transform, source: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        let transform = transform.normalize();
+        let path = self
+            .directory
+            .join(format!("{}{}{}", name, SEPARATOR, transform.key()));
+
+        if let Ok(data) = fs::read(&path) {
+            return Ok(data);
+        }
+
+        let data = transform.apply(&source()?)?;
+
+        if let Err(err) = write_file(&path, &data) {
+            warn!("Could not cache transform {}: {}", path.display(), err);
+        }
+
+        Ok(data)
+    }
+
+    /// Remove the cached transforms of files which are no longer in `storage`
+    pub fn collect_garbage(&self, storage: &Storage) -> Result<()> {
+        let mut removed = 0;
+
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            let source = match name.find(SEPARATOR) {
+                Some(pos) => &name[..pos],
+                None => continue,
+            };
+
+            if storage.path(source).map_or(false, |path| path.exists()) {
+                continue;
+            }
+
+            match fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(err) => warn!("Could not remove transform {}: {}", name, err),
+            }
+        }
+
+        info!("Removed {} unused transforms", removed);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::detect_format;
+    use image::ImageFormat;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    fn dimensions(data: &[u8]) -> (u32, u32) {
+        image::load_from_memory(data).unwrap().dimensions()
+    }
+
+    #[test]
+    fn test_apply() {
+        let scaled = Transform::scale(40, Format::Webp)
+            .apply(&png(100, 50))
+            .unwrap();
+        assert_eq!(detect_format(&scaled), Some("webp"));
+        assert_eq!(dimensions(&scaled), (40, 20));
+
+        let avif = Transform::scale(40, Format::Avif)
+            .apply(&png(100, 50))
+            .unwrap();
+        assert_eq!(&avif[4..12], b"ftypavif");
+
+        let cropped = Transform {
+            width: Some(30),
+            height: Some(30),
+            fit: Fit::Crop,
+            format: Format::Jpeg,
+            quality: Some(50),
+        }
+        .apply(&png(100, 50))
+        .unwrap();
+        assert_eq!(detect_format(&cropped), Some("jpg"));
+        assert_eq!(dimensions(&cropped), (30, 30));
+
+        let small = Transform::scale(400, Format::Png)
+            .apply(&png(100, 50))
+            .unwrap();
+        assert_eq!(
+            dimensions(&small),
+            (100, 50),
+            "small images are not scaled up"
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let transform = Transform::scale(320, Format::Jpeg);
+
+        assert_eq!(
+            transform.normalize(),
+            Transform {
+                quality: Some(DEFAULT_QUALITY),
+                ..transform
+            }
+            .normalize()
+        );
+        assert_eq!(
+            Transform::scale(320, Format::Png).normalize(),
+            Transform {
+                quality: Some(20),
+                ..Transform::scale(320, Format::Png)
+            }
+            .normalize(),
+            "png ignores the quality"
+        );
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("JPG".parse(), Ok(Format::Jpeg));
+        assert_eq!("webp".parse(), Ok(Format::Webp));
+        assert_eq!("avif".parse(), Ok(Format::Avif));
+        assert!("bmp".parse::<Format>().unwrap_err().contains("Unsupported"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let crop = Transform {
+            width: Some(100),
+            height: None,
+            fit: Fit::Crop,
+            format: Format::Webp,
+            quality: None,
+        };
+
+        assert!(Transform::scale(320, Format::Webp).validate().is_ok());
+        assert!(Transform::scale(0, Format::Webp).validate().is_err());
+        assert!(crop.validate().is_err());
+        assert!(Transform {
+            quality: Some(101),
+            ..Transform::scale(320, Format::Webp)
+        }
+        .validate()
+        .is_err());
+    }
+}
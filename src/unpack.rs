@@ -0,0 +1,365 @@
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::GzDecoder;
+use std::{
+    cmp::Ordering,
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path},
+};
+use tar::EntryType;
+use zip::ZipArchive;
+
+/// Bounds on what an uploaded archive may expand to, protecting against
+/// archives which decompress to huge amounts of data
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of files in the archive
+    pub entries: usize,
+    /// Maximum size of a single extracted file in bytes
+    pub entry_size: u64,
+    /// Maximum size of all extracted files together in bytes
+    pub total_size: u64,
+}
+
+/// Number of bytes at the start of an archive needed to detect its format
+pub const HEAD_SIZE: u64 = 512;
+
+/// A regular file extracted from an archive
+#[derive(Debug)]
+pub struct Entry {
+    /// Path of the file inside the archive
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// Returns true if `data`, the first [`HEAD_SIZE`] bytes of a file, look
+/// like a ZIP, tar or gzipped tar archive
+pub fn is_archive(data: &[u8]) -> bool {
+    detect(data).is_some()
+}
+
+fn detect(data: &[u8]) -> Option<Format> {
+    match data {
+        [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => Some(Format::Zip),
+        [0x1f, 0x8b, ..] => Some(Format::TarGz),
+        _ if data.get(257..262) == Some(b"ustar") => Some(Format::Tar),
+        _ => None,
+    }
+}
+
+/// Extract the regular files from `archive` and pass them to `each` one
+/// after another, in the order they are stored.
+///
+/// Only one file is held in memory at a time; callers sort the files with
+/// [`natural_cmp`] if needed. Fails if an entry tries to escape the archive
+/// with an absolute path or `..`, or if the archive exceeds `limits`. Hidden
+/// files like `.DS_Store` and the `__MACOSX` resource forks are left out.
+pub fn extract<R, F>(mut archive: R, limits: Limits, each: F) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(Entry) -> Result<()>,
+{
+    let mut head = Vec::new();
+    (&mut archive)
+        .take(HEAD_SIZE)
+        .read_to_end(&mut head)
+        .context("Could not read archive")?;
+    archive
+        .seek(SeekFrom::Start(0))
+        .context("Could not read archive")?;
+
+    let mut extractor = Extractor::new(limits, each);
+    match detect(&head).context("Unsupported archive format")? {
+        Format::Zip => extract_zip(archive, &mut extractor),
+        Format::Tar => extract_tar(archive, &mut extractor),
+        // the decompressed stream is bounded, so a gzip bomb can not keep us busy
+        Format::TarGz => extract_tar(
+            GzDecoder::new(archive).take(limits.total_size.saturating_mul(2)),
+            &mut extractor,
+        ),
+    }
+}
+
+fn extract_zip<R, F>(archive: R, extractor: &mut Extractor<F>) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(Entry) -> Result<()>,
+{
+    let mut archive = ZipArchive::new(archive).context("Invalid ZIP archive")?;
+
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).context("Invalid ZIP archive")?;
+        if file.is_dir() {
+            continue;
+        }
+
+        let name = file.name().to_string();
+        extractor.add(name, file)?;
+    }
+
+    Ok(())
+}
+
+fn extract_tar<R, F>(reader: R, extractor: &mut Extractor<F>) -> Result<()>
+where
+    R: Read,
+    F: FnMut(Entry) -> Result<()>,
+{
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries().context("Invalid tar archive")? {
+        let entry = entry.context("Invalid tar archive")?;
+        // links and devices have no content we could use
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        let name = entry.path()?.to_string_lossy().to_string();
+        extractor.add(name, entry)?;
+    }
+
+    Ok(())
+}
+
+/// Passes on entries while enforcing the limits
+struct Extractor<F> {
+    limits: Limits,
+    count: usize,
+    total_size: u64,
+    each: F,
+}
+
+impl<F> Extractor<F>
+where
+    F: FnMut(Entry) -> Result<()>,
+{
+    fn new(limits: Limits, each: F) -> Self {
+        Extractor {
+            limits,
+            count: 0,
+            total_size: 0,
+            each,
+        }
+    }
+
+    fn add<R: Read>(&mut self, name: String, reader: R) -> Result<()> {
+        ensure!(is_safe_path(&name), "Invalid path in archive: {}", name);
+
+        self.count += 1;
+        ensure!(
+            self.count <= self.limits.entries,
+            "Archive contains more than {} files",
+            self.limits.entries
+        );
+
+        if is_hidden(&name) {
+            return Ok(());
+        }
+
+        // sizes in archive headers can not be trusted, so read at most one byte too many
+        let mut data = Vec::new();
+        reader
+            .take(self.limits.entry_size + 1)
+            .read_to_end(&mut data)
+            .with_context(|| format!("Could not extract {}", name))?;
+
+        if data.len() as u64 > self.limits.entry_size {
+            bail!("{} is larger than {} bytes", name, self.limits.entry_size);
+        }
+
+        self.total_size += data.len() as u64;
+        ensure!(
+            self.total_size <= self.limits.total_size,
+            "Archive expands to more than {} bytes",
+            self.limits.total_size
+        );
+
+        (self.each)(Entry { name, data })
+    }
+}
+
+/// Returns true if `name` stays inside the directory the archive is extracted to
+fn is_safe_path(name: &str) -> bool {
+    // archives made on windows may use backslashes
+    let name = name.replace('\\', "/");
+
+    !name.is_empty()
+        && Path::new(&name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Returns true for hidden files and files inside hidden directories
+fn is_hidden(name: &str) -> bool {
+    name.split(|c| c == '/' || c == '\\')
+        .any(|part| (part.starts_with('.') && part != ".") || part == "__MACOSX")
+}
+
+/// Compare file names so that numbers are ordered by their value, e.g.
+/// `image2.png` before `image10.png`
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+
+    loop {
+        let ((x, x_rest), (y, y_rest)) = match (next_chunk(a), next_chunk(b)) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => (x, y),
+        };
+
+        let is_number = |s: &str| s.starts_with(|c: char| c.is_ascii_digit());
+        let ordering = if is_number(x) && is_number(y) {
+            let (x_value, y_value) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+            x_value
+                .len()
+                .cmp(&y_value.len())
+                .then_with(|| x_value.cmp(y_value))
+                .then_with(|| x.len().cmp(&y.len()))
+        } else {
+            x.cmp(y)
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+
+        a = x_rest;
+        b = y_rest;
+    }
+}
+
+/// Split `s` after its leading run of digits or non-digits
+fn next_chunk(s: &str) -> Option<(&str, &str)> {
+    let digits = s.chars().next()?.is_ascii_digit();
+    let end = s
+        .find(|c: char| c.is_ascii_digit() != digits)
+        .unwrap_or_else(|| s.len());
+
+    Some(s.split_at(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    const LIMITS: Limits = Limits {
+        entries: 10,
+        entry_size: 100,
+        total_size: 150,
+    };
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            tar.append_data(&mut header, name, *data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    /// Extract all entries of `data`, sorted by name
+    fn extract_all(data: &[u8], limits: Limits) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        extract(Cursor::new(data), limits, |entry| {
+            entries.push(entry);
+            Ok(())
+        })?;
+        entries.sort_by(|a, b| natural_cmp(&a.name, &b.name));
+        Ok(entries)
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_extract() {
+        let files: &[(&str, &[u8])] = &[
+            ("b/image10.png", b"10"),
+            ("b/image2.png", b"2"),
+            ("a.png", b"a"),
+            ("__MACOSX/._a.png", b"fork"),
+            (".DS_Store", b"junk"),
+        ];
+
+        for data in &[zip(files), tar(files)] {
+            assert!(is_archive(data));
+            let entries = extract_all(data, LIMITS).unwrap();
+            assert_eq!(
+                names(&entries),
+                vec!["a.png", "b/image2.png", "b/image10.png"]
+            );
+            assert_eq!(entries[2].data, b"10");
+        }
+    }
+
+    #[test]
+    fn test_extract_callback_error() {
+        let mut count = 0;
+        let result = extract(
+            Cursor::new(zip(&[("a.png", b"a"), ("b.png", b"b")])),
+            LIMITS,
+            |_| {
+                count += 1;
+                bail!("Stop")
+            },
+        );
+
+        assert!(result.is_err());
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_extract_limits() {
+        let large = [0; 101];
+        assert!(extract_all(&zip(&[("a.png", &large)]), LIMITS).is_err());
+
+        let half = [0; 80];
+        assert!(extract_all(&zip(&[("a.png", &half), ("b.png", &half)]), LIMITS).is_err());
+
+        let files: Vec<_> = (0..11).map(|i| (format!("{}.png", i), b"x")).collect();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), &data[..]))
+            .collect();
+        assert!(extract_all(&zip(&files), LIMITS).is_err());
+    }
+
+    #[test]
+    fn test_extract_traversal() {
+        assert!(extract_all(&zip(&[("../evil.png", b"x")]), LIMITS).is_err());
+        assert!(extract_all(&zip(&[("/etc/evil.png", b"x")]), LIMITS).is_err());
+        assert!(extract_all(&zip(&[("a\\..\\..\\evil.png", b"x")]), LIMITS).is_err());
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["img10.png", "img2.png", "img02.png", "IMG1.png", "img1.png"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            vec!["IMG1.png", "img1.png", "img2.png", "img02.png", "img10.png"]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{config::Config, storage::Storage};
+use multipart::server::Multipart;
+use rocket::{
+    data::{FromDataSimple, Outcome},
+    http::Status,
+    response::status::Custom,
+    Data,
+    Outcome::*,
+    Request, State,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+/// A file sent as part of a multipart form, kept in a temporary file
+#[derive(Debug)]
+pub struct UploadedFile {
+    pub filename: Option<String>,
+    file: File,
+    size: u64,
+}
+
+impl UploadedFile {
+    /// Name of the file for messages
+    pub fn name(&self) -> &str {
+        self.filename.as_deref().unwrap_or("upload")
+    }
+
+    /// Read the first `len` bytes, e.g. to detect the file format
+    pub fn head(&mut self, len: u64) -> Result<Vec<u8>, Custom<String>> {
+        let mut head = Vec::new();
+        self.rewind()?;
+        (&mut self.file)
+            .take(len)
+            .read_to_end(&mut head)
+            .map_err(read_error)?;
+        Ok(head)
+    }
+
+    /// Read the whole file into memory
+    pub fn read(mut self) -> Result<Vec<u8>, Custom<String>> {
+        let mut data = Vec::new();
+        self.rewind()?;
+        self.file.read_to_end(&mut data).map_err(read_error)?;
+        Ok(data)
+    }
+
+    /// Get the temporary file, positioned at its start
+    pub fn into_file(mut self) -> Result<File, Custom<String>> {
+        self.rewind()?;
+        Ok(self.file)
+    }
+
+    fn rewind(&mut self) -> Result<(), Custom<String>> {
+        self.file
+            .seek(SeekFrom::Start(0))
+            .map(|_| ())
+            .map_err(read_error)
+    }
+}
+
+fn read_error(err: io::Error) -> Custom<String> {
+    Custom(
+        Status::InternalServerError,
+        format!("Could not read upload: {}", err),
+    )
+}
+
+/// A `multipart/form-data` request body.
+///
+/// The body and its files are written to temporary files instead of being
+/// kept in memory. Its size is limited by the `max-upload-size` storage
+/// setting.
+#[derive(Debug)]
+pub struct MultipartForm {
+    fields: HashMap<String, String>,
+    files: HashMap<String, UploadedFile>,
+}
+
+impl MultipartForm {
+    /// Get the text field `name`
+    pub fn field(&self, name: &str) -> Result<&str, Custom<String>> {
+        self.fields.get(name).map(String::as_str).ok_or_else(|| {
+            Custom(
+                Status::BadRequest,
+                format!("Invalid form input: missing field `{}`", name),
+            )
+        })
+    }
+
+    /// Take the file field `name` out of the form
+    pub fn take_file(&mut self, name: &str) -> Result<UploadedFile, Custom<String>> {
+        self.files
+            .remove(name)
+            .filter(|file| file.size > 0)
+            .ok_or_else(|| {
+                Custom(
+                    Status::BadRequest,
+                    format!("Invalid form input: missing file `{}`", name),
+                )
+            })
+    }
+}
+
+impl FromDataSimple for MultipartForm {
+    type Error = Custom<String>;
+
+    fn from_data(request: &Request, data: Data) -> Outcome<Self, Self::Error> {
+        let boundary = match request.content_type().and_then(|ct| {
+            if ct.is_form_data() {
+                ct.params().find(|&(key, _)| key == "boundary")
+            } else {
+                None
+            }
+        }) {
+            Some((_, boundary)) => boundary.to_string(),
+            None => {
+                return Forward(data);
+            }
+        };
+
+        let limit = match request.guard::<State<Config>>() {
+            Success(config) => config.storage.max_upload_size,
+            _ => return failure(Status::InternalServerError, "Missing config".into()),
+        };
+
+        let storage = match request.guard::<State<Storage>>() {
+            Success(storage) => storage,
+            _ => return failure(Status::InternalServerError, "Missing storage".into()),
+        };
+        let temp_file = || {
+            storage
+                .temp_file()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:#}", err)))
+        };
+
+        let body = temp_file().and_then(|mut body| {
+            let size = io::copy(&mut data.open().take(limit + 1), &mut body)?;
+            body.seek(SeekFrom::Start(0))?;
+            Ok((body, size))
+        });
+        let body = match body {
+            Ok((_, size)) if size > limit => {
+                return failure(
+                    Status::PayloadTooLarge,
+                    format!("Upload is larger than {} bytes", limit),
+                )
+            }
+            Ok((body, _)) => body,
+            Err(err) => {
+                return failure(
+                    Status::BadRequest,
+                    format!("Could not read upload: {}", err),
+                )
+            }
+        };
+
+        match parse(body, boundary, temp_file) {
+            Ok(form) => Success(form),
+            Err(err) => failure(Status::BadRequest, format!("Invalid form input: {}", err)),
+        }
+    }
+}
+
+fn failure<S>(status: Status, message: String) -> Outcome<S, Custom<String>> {
+    Failure((status, Custom(status, message)))
+}
+
+fn parse<R, F>(body: R, boundary: String, temp_file: F) -> io::Result<MultipartForm>
+where
+    R: Read,
+    F: Fn() -> io::Result<File>,
+{
+    let mut multipart = Multipart::with_body(body, boundary);
+    let mut fields = HashMap::new();
+    let mut files = HashMap::new();
+
+    while let Some(mut entry) = multipart.read_entry()? {
+        let name = entry.headers.name.to_string();
+
+        if entry.headers.filename.is_none() {
+            let mut data = Vec::new();
+            entry.data.read_to_end(&mut data)?;
+            let value = String::from_utf8(data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.utf8_error()))?;
+            fields.insert(name, value);
+        } else {
+            let mut file = temp_file()?;
+            let size = io::copy(&mut entry.data, &mut file)?;
+            files.insert(
+                name,
+                UploadedFile {
+                    filename: entry.headers.filename.clone(),
+                    file,
+                    size,
+                },
+            );
+        }
+    }
+
+    Ok(MultipartForm { fields, files })
+}
@@ -1,15 +1,20 @@
-use rocket::http::{ContentType, Status};
-use rocket::local::Client;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::{Client, LocalResponse};
+use std::io::{BufRead, BufReader, Cursor, Write};
+use std::net::TcpListener;
+use std::thread;
 use v::rocket;
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
 
 #[test]
 fn new() {
     let client = Client::new(v::rocket()).expect("valid rocket instance");
+    let url = format!("{}VoyouQH.png", serve(PNG));
 
     let mut response = client
         .post("/a/new")
         .header(ContentType::Form)
-        .body("title=title&url=https%3A%2F%2Fi.imgur.com%2FVoyouQH.png")
+        .body(format!("title=title&url={}", url))
         .dispatch();
 
     assert_eq!(response.status(), Status::Created);
@@ -17,6 +22,44 @@ fn new() {
     assert!(response.body().is_some());
 }
 
+#[test]
+fn new_long_link() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+    let url = format!("{}{}.png", serve(PNG), "a".repeat(300));
+
+    let response = client
+        .post("/a/new")
+        .header(ContentType::Form)
+        .body(format!("title=title&url={}", url))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Created);
+}
+
+#[test]
+fn new_many_links() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+    let links: Vec<_> = (0..10)
+        .map(|number| format!("http://localhost/{}.png", number))
+        .collect();
+
+    let mut response = client
+        .post("/a/new")
+        .header(ContentType::Form)
+        .body(format!("title=title&url={}", links.join("%0D%0A")))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Created);
+    assert!(response.body_string().unwrap().contains("/import-status"));
+    let location = response.headers().get_one("Location").unwrap();
+
+    let mut response = client.get(format!("{}/import-status", location)).dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("Adding images: queued"));
+}
+
 #[test]
 fn new_invalid_image_url() {
     let client = Client::new(v::rocket()).expect("valid rocket instance");
@@ -33,18 +76,116 @@ fn new_invalid_image_url() {
 }
 
 #[test]
-fn import() {
+fn new_invalid_lines() {
     let client = Client::new(v::rocket()).expect("valid rocket instance");
 
     let mut response = client
+        .post("/a/new")
+        .header(ContentType::Form)
+        .body("title=&url=https%3A%2F%2Fi.evil.com%2Fa.png%0D%0A%0D%0Anot+a+link%0D%0A")
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("Line 1: "));
+    assert!(!body.contains("Line 2: "));
+    assert!(body.contains("Line 3: "));
+}
+
+#[test]
+fn new_without_links() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = client
+        .post("/a/new")
+        .header(ContentType::Form)
+        .body("title=title&url=%0D%0A+%0D%0A")
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn import() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = client
         .post("/a/import")
         .header(ContentType::Form)
         .body("title=&url=https%3A%2F%2Fimgur.com%2Fa%2FJrheYnV")
         .dispatch();
 
-    assert_eq!(response.status(), Status::Created);
-    assert_eq!(response.content_type(), Some(ContentType::HTML));
-    assert!(response.body().is_some());
+    assert_eq!(response.status(), Status::SeeOther);
+    let location = response.headers().get_one("Location").unwrap();
+    assert!(location.ends_with("/import-status"));
+
+    let mut response = client.get(location).dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("Your deletion token"));
+    assert!(body.contains("https://imgur.com/a/JrheYnV"));
+}
+
+#[test]
+fn import_status_requires_import() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+
+    let response = client.get(format!("{}/import-status", location)).dispatch();
+
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn import_invalid_link() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    for url in &[
+        "https%3A%2F%2Fimgur.com",
+        "https%3A%2F%2Fexample.com%2Fa%2FJrheYnV",
+    ] {
+        let response = client
+            .post("/a/import")
+            .header(ContentType::Form)
+            .body(format!("title=&url={}", url))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+}
+
+#[test]
+fn scrape_invalid_link() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = client
+        .post("/a/scrape")
+        .header(ContentType::Form)
+        .body("url=file%3A%2F%2F%2Fetc%2Fpasswd")
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn pick_invalid_images() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    for body in &[
+        "title=title",
+        "title=title&url=https%3A%2F%2Fi.evil.com%2Fa.png&url=https%3A%2F%2Fi.evil.com%2Fb.png",
+    ] {
+        let response = client
+            .post("/a/scrape/create")
+            .header(ContentType::Form)
+            .body(*body)
+            .dispatch();
+
+        assert_eq!(response.status(), Status::BadRequest);
+    }
 }
 
 #[test]
@@ -95,3 +236,395 @@ fn create_album_and_insert() {
 
     assert_eq!(response.status(), Status::Created);
 }
+
+#[test]
+fn get_invalid_page() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    for url in &[
+        "/a/this_token_does_not_exist?page=0",
+        "/a/this_token_does_not_exist?limit=100000",
+    ] {
+        let response = client.get(*url).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+}
+
+/// A 1x1 transparent PNG
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0\x1f\x15\xc4\x89\0\0\0\x0bIDATx\x9cc`\0\x02\0\0\x05\0\x01z^\xab?\0\0\0\0IEND\xaeB`\x82";
+
+/// Serve `image` as PNG for every path on a local port, which must be an
+/// allowed domain, and return the base url of the server
+fn serve(image: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            let body = if request.starts_with("HEAD ") {
+                &[]
+            } else {
+                image
+            };
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                image.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+        }
+    });
+
+    format!("http://localhost:{}/", port)
+}
+
+fn multipart_body(boundary: &str, title: &str, file: &[u8]) -> Vec<u8> {
+    let mut body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\n{t}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+         Content-Type: image/png\r\n\r\n",
+        b = boundary,
+        t = title
+    )
+    .into_bytes();
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+fn upload_album<'c>(client: &'c Client, file: &[u8]) -> LocalResponse<'c> {
+    let boundary = "X-BOUNDARY";
+
+    client
+        .post("/a/upload")
+        .header(ContentType::with_params(
+            "multipart",
+            "form-data",
+            ("boundary", boundary),
+        ))
+        .body(multipart_body(boundary, "title", file))
+        .dispatch()
+}
+
+/// Find the first link starting with `prefix` on the page at `location`
+fn find_link(client: &Client, location: &str, prefix: &str) -> String {
+    let body = client
+        .get(location.to_string())
+        .dispatch()
+        .body_string()
+        .unwrap();
+    let start = body.find(prefix).expect("page contains link");
+    let end = start + body[start..].find(|c| c == '"' || c == ' ').unwrap();
+    body[start..end].to_string()
+}
+
+/// Get the url of the first image in the album at `location`
+fn first_image_url(client: &Client, location: &str) -> String {
+    find_link(client, location, "/f/")
+}
+
+#[test]
+fn upload() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+
+    assert_eq!(response.status(), Status::Created);
+    let location = response.headers().get_one("Location").unwrap();
+
+    let mut response = client.get(first_image_url(&client, location)).dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    assert_eq!(response.body_bytes(), Some(PNG.to_vec()));
+}
+
+#[test]
+fn upload_deduplicates() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let first = upload_album(&client, PNG);
+    let second = upload_album(&client, PNG);
+
+    assert_eq!(
+        first_image_url(&client, first.headers().get_one("Location").unwrap()),
+        first_image_url(&client, second.headers().get_one("Location").unwrap()),
+    );
+}
+
+#[test]
+fn thumbnail() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+    let thumbnail = find_link(&client, location, "/thumb/");
+
+    let response = client.get(thumbnail.clone()).dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+
+    let (base, _) = thumbnail.split_at(thumbnail.rfind('/').unwrap());
+    let response = client.get(format!("{}/123", base)).dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn transform() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+    let name = first_image_url(&client, location).replace("/f/", "");
+
+    let response = client
+        .get(format!("/transform/{}?w=320&format=webp", name))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::WEBP));
+
+    let response = client
+        .get(format!("/transform/{}?w=321&format=webp", name))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = client
+        .get(format!("/transform/{}?w=320&format=avif", name))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.content_type(),
+        Some(ContentType::new("image", "avif"))
+    );
+}
+
+#[test]
+fn download() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+
+    let mut response = client.get(format!("{}/download", location)).dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::ZIP));
+    assert!(response
+        .headers()
+        .get_one("Content-Disposition")
+        .unwrap()
+        .starts_with("attachment"));
+
+    let archive = ZipArchive::new(Cursor::new(response.body_bytes().unwrap())).unwrap();
+    assert_eq!(archive.len(), 1);
+}
+
+#[test]
+fn manifest() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+
+    let mut response = client
+        .get(format!("{}/manifest.json", location))
+        .header(Header::new("Host", "v.example.com"))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let manifest: serde_json::Value =
+        serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(manifest["version"], 1);
+    assert_eq!(manifest["title"], "title");
+    // without a configured public link the link stays relative
+    assert_eq!(
+        manifest["images"][0]["url"],
+        first_image_url(&client, location)
+    );
+    assert_eq!(manifest["images"][0]["metadata"]["width"], 1);
+}
+
+#[test]
+fn import_manifest_stored() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+    let url = first_image_url(&client, location);
+
+    let response = client
+        .post("/a/manifest")
+        .header(ContentType::JSON)
+        .body(format!(
+            r#"{{"version": 1, "images": [{{"url": "{}", "caption": "Restored"}}]}}"#,
+            url
+        ))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Created);
+    let location = response.headers().get_one("Location").unwrap();
+    assert_eq!(first_image_url(&client, location), url);
+}
+
+#[test]
+fn import_manifest_spoofed_host() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+    let url = first_image_url(&client, location);
+
+    // the Host header does not make another server look like this instance
+    let mut response = client
+        .post("/a/manifest")
+        .header(ContentType::JSON)
+        .header(Header::new("Host", "evil.example"))
+        .body(format!(
+            r#"{{"version": 1, "images": [{{"url": "http://evil.example{}"}}]}}"#,
+            url
+        ))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(response.body_string().unwrap().contains("not allowed"));
+}
+
+#[test]
+fn import_manifest_invalid() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+    let import = |manifest: &str| {
+        client
+            .post("/a/manifest")
+            .header(ContentType::JSON)
+            .body(manifest.to_string())
+            .dispatch()
+    };
+
+    let mut response = import(r#"{"version": 99, "images": []}"#);
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(response.body_string().unwrap().contains("Unsupported"));
+
+    let mut response = import(
+        r#"{"version": 1, "images": [{"url": "/f/a.png"}, {"url": "https://i.evil.com/b.png"}]}"#,
+    );
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("Image 1: "));
+    assert!(body.contains("Image 2: "));
+}
+
+fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, data) in files {
+        zip.start_file(*name, FileOptions::default()).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[test]
+fn upload_archive() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let archive = zip(&[("2.png", PNG), ("10.png", PNG), ("readme.txt", b"hi")]);
+    let mut response = upload_album(&client, &archive);
+
+    assert_eq!(response.status(), Status::Created);
+    assert!(response
+        .body_string()
+        .unwrap()
+        .contains("Skipped readme.txt"));
+
+    let archive = zip(&[("../evil.png", PNG)]);
+    let response = upload_album(&client, &archive);
+
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn upload_invalid_file() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, b"<!doctype html>");
+
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn resync_requires_import() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let mut response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap().to_string();
+    let body = response.body_string().unwrap();
+    let start = body.find("class=\"token\">").unwrap() + "class=\"token\">".len();
+    let deletion_token = &body[start..start + body[start..].find('<').unwrap()];
+
+    let response = client
+        .post(format!("{}/resync", location))
+        .header(ContentType::Form)
+        .body(format!("deletion_token={}", deletion_token))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = client
+        .post(format!("{}/resync", location))
+        .header(ContentType::Form)
+        .body("deletion_token=wrong")
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn broken_links_require_auth() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap();
+
+    let response = client.get(format!("{}/broken", location)).dispatch();
+
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn edit_shows_total_size() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let mut response = upload_album(&client, PNG);
+    let location = response.headers().get_one("Location").unwrap().to_string();
+    let body = response.body_string().unwrap();
+    let start = body.find("<a class=\"token\">").unwrap() + "<a class=\"token\">".len();
+    let deletion_token = &body[start..start + body[start..].find('<').unwrap()];
+
+    client
+        .post(format!("{}/auth", location))
+        .header(ContentType::Form)
+        .body(format!("deletion_token={}", deletion_token))
+        .dispatch();
+    let mut response = client.get(format!("{}/edit", location)).dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response
+        .body_string()
+        .unwrap()
+        .contains(&format!("1 images, {} B stored", PNG.len())));
+}
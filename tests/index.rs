@@ -43,6 +43,18 @@ fn feelsdankman() {
     assert_eq!(response.body_string(), Some(String::new()));
 }
 
+#[test]
+fn import_lists_sources() {
+    let client = Client::new(v::rocket()).expect("valid rocket instance");
+
+    let mut response = client.get("/import").dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("Imgur"));
+    assert!(body.contains("Reddit"));
+}
+
 #[test]
 fn unsupported_methods() {
     let client = Client::new(v::rocket()).expect("valid rocket instance");